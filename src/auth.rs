@@ -0,0 +1,187 @@
+//! Short-lived access tokens and their refresh via the OAuth2 refresh token.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs as tokio_fs;
+use tokio::sync::{Mutex, RwLock};
+
+use crate::config::Config;
+use crate::dropbox::DropboxClient;
+use crate::http::with_retries;
+
+mod login;
+mod store;
+
+pub use login::login;
+pub(crate) use store::keyring_refresh_token;
+use store::{load_short_token, save_short_token};
+
+/// A short-lived access token as persisted in `short_token_file`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShortToken {
+    pub access_token: String,
+    /// Unknown for token files written before expiry was tracked.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ShortToken {
+    /// Whether the token is expired or will be within `skew`.
+    pub fn expires_within(&self, skew: Duration) -> bool {
+        self.expires_at.is_some_and(|expires_at| {
+            Utc::now() + chrono::Duration::from_std(skew).unwrap_or_default()
+                >= expires_at
+        })
+    }
+
+    /// Whether `access_token` could be a real token: not empty and without
+    /// whitespace or control characters, which a file cut short or edited by
+    /// hand may leave in it.
+    pub(crate) fn is_plausible(&self) -> bool {
+        !self.access_token.is_empty()
+            && !self.access_token.chars().any(|c| c.is_whitespace() || c.is_control())
+    }
+}
+
+pub(crate) fn read_short_token_file(path: &Path) -> Result<Option<ShortToken>> {
+    let buf = match fs::read_to_string(path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Read short token file {:?}", path));
+        }
+    };
+    // Older versions stored the bare token without any expiry. JSON that does
+    // not parse is a write cut short rather than one of those.
+    let token = match serde_json::from_str::<ShortToken>(&buf) {
+        Ok(token) => token,
+        Err(_) if buf.trim_start().starts_with('{') => {
+            warn!("Ignoring unreadable short token file {:?}", path);
+            return Ok(None);
+        }
+        Err(_) => ShortToken { access_token: buf.trim().to_string(), expires_at: None },
+    };
+    if !token.is_plausible() {
+        warn!("Ignoring short token file {:?}: it holds no usable token", path);
+        return Ok(None);
+    }
+    Ok(Some(token))
+}
+
+/// The short-lived token shared by all concurrent uploads. Refreshes are
+/// single-flight: whoever takes `refresh_lock` first fetches a new token and
+/// everyone queued behind it reuses the result instead of refreshing again.
+#[derive(Clone, Default)]
+pub struct TokenCache {
+    current: Arc<RwLock<Option<ShortToken>>>,
+    refresh_lock: Arc<Mutex<()>>,
+}
+
+impl TokenCache {
+    /// Returns a token that is not about to expire, loading it from
+    /// `short_token_file` or refreshing it as needed.
+    pub async fn get(
+        &self,
+        client: &impl DropboxClient,
+        config: &Config,
+    ) -> Result<String> {
+        if let Some(token) = self.valid(config).await {
+            return Ok(token);
+        }
+        let _guard = self.refresh_lock.lock().await;
+        if let Some(token) = self.valid(config).await {
+            return Ok(token);
+        }
+        match load_short_token(config).await? {
+            Some(token) if !token.expires_within(config.token_expiry_skew) => {
+                let access_token = token.access_token.clone();
+                *self.current.write().await = Some(token);
+                return Ok(access_token);
+            }
+            Some(_) => info!("Short-lived token is about to expire, refreshing..."),
+            None => warn!("No saved short-lived token, requesting new token..."),
+        }
+        self.fetch(client, config).await
+    }
+
+    /// Replaces `stale`, a token Dropbox just rejected, unless another task has
+    /// already done so.
+    pub async fn refresh(
+        &self,
+        client: &impl DropboxClient,
+        config: &Config,
+        stale: &str,
+    ) -> Result<String> {
+        let _guard = self.refresh_lock.lock().await;
+        if let Some(token) = self.valid(config).await
+            && token != stale
+        {
+            return Ok(token);
+        }
+        self.fetch(client, config).await
+    }
+
+    async fn valid(&self, config: &Config) -> Option<String> {
+        self.current
+            .read()
+            .await
+            .as_ref()
+            .filter(|token| !token.expires_within(config.token_expiry_skew))
+            .map(|token| token.access_token.clone())
+    }
+
+    /// Must be called with `refresh_lock` held.
+    async fn fetch(
+        &self,
+        client: &impl DropboxClient,
+        config: &Config,
+    ) -> Result<String> {
+        let token = refresh_token(client, config).await?;
+        save_short_token(config, &token).await?;
+        let access_token = token.access_token.clone();
+        *self.current.write().await = Some(token);
+        Ok(access_token)
+    }
+}
+
+/// Writes `token` to `path` through a temporary file renamed into place, so a
+/// reader sees either the old token or the new one and never part of it. Writes
+/// from this process are already one at a time under `refresh_lock`; the pid in
+/// the temporary name keeps other processes sharing the file out of its way.
+pub(crate) async fn write_short_token(path: &Path, token: &ShortToken) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio_fs::create_dir_all(parent).await.ok();
+    }
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = path.with_file_name(temp_name);
+    let written = async {
+        tokio_fs::write(&temp, serde_json::to_vec(token)?).await?;
+        tokio_fs::rename(&temp, path).await
+    }
+    .await;
+    if written.is_err() {
+        tokio_fs::remove_file(&temp).await.ok();
+    }
+    written.with_context(|| format!("Write short token file: {:?}", path))
+}
+
+/// Exchanges the configured refresh token for a new short-lived access token,
+/// retrying 5xx responses, rate limits and connection errors as
+/// `token_refresh_retry` allows.
+pub async fn refresh_token(
+    client: &impl DropboxClient,
+    config: &Config,
+) -> Result<ShortToken> {
+    info!("Requesting new short-lived access token...");
+    Ok(with_retries(&config.token_refresh_retry, "Token refresh", || {
+        client.refresh_token(config)
+    })
+    .await?)
+}
@@ -0,0 +1,251 @@
+//! Obtaining the refresh token in the first place: the OAuth2 authorization
+//! code flow with PKCE, answered by a one-shot listener on localhost.
+
+use anyhow::{Context, Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use log::{info, warn};
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::store::save_keyring_refresh_token;
+use crate::config::{
+    DEFAULT_API_REFRESH_ADDRESS, DEFAULT_CONNECT_TIMEOUT_SECS,
+    DEFAULT_REQUEST_TIMEOUT_SECS, DEFAULT_USER_AGENT, ProxySettings, Settings,
+    TokenStore,
+};
+use crate::http::{check_response, http_client};
+
+const DEFAULT_AUTHORIZE_ADDRESS: &str = "https://www.dropbox.com/oauth2/authorize";
+/// How long the user has to finish in the browser.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Has the user authorize the app (`APP_KEY`) in the browser, exchanges the
+/// code for a refresh token at `API_REFRESH_ADDRESS` and saves it as
+/// `REFRESH_TOKEN` in `env_file`, replacing any previous value, or with
+/// `TOKEN_STORE=keyring` in the keyring if it can be reached.
+///
+/// The redirect goes to `http://127.0.0.1:<port>/callback`, which must be
+/// listed among the app's redirect URIs.
+pub async fn login(settings: &Settings, env_file: &Path, port: u16) -> Result<()> {
+    let app_key = settings.required("APP_KEY")?;
+    let token_address =
+        settings.url("API_REFRESH_ADDRESS", DEFAULT_API_REFRESH_ADDRESS)?;
+    let authorize_address =
+        settings.url("API_AUTHORIZE_ADDRESS", DEFAULT_AUTHORIZE_ADDRESS)?;
+    let client = http_client(
+        &ProxySettings::from_settings(settings),
+        &settings.lookup("USER_AGENT").unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+        Duration::from_secs(
+            settings.parse("CONNECT_TIMEOUT_SECS", DEFAULT_CONNECT_TIMEOUT_SECS)?,
+        ),
+        Duration::from_secs(
+            settings.parse("REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT_SECS)?,
+        ),
+    )?;
+
+    let verifier = random_string(64);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let state = random_string(32);
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Listening for the redirect on port {}", port))?;
+    let redirect_uri =
+        format!("http://127.0.0.1:{}/callback", listener.local_addr()?.port());
+
+    let mut url = reqwest::Url::parse(&authorize_address).with_context(|| {
+        format!("Invalid API_AUTHORIZE_ADDRESS {:?}", authorize_address)
+    })?;
+    url.query_pairs_mut()
+        .append_pair("client_id", &app_key)
+        .append_pair("response_type", "code")
+        .append_pair("token_access_type", "offline")
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("state", &state);
+    println!("Open this URL to allow access to your Dropbox:\n\n    {}\n", url);
+    open_browser(url.as_str());
+
+    let code = tokio::time::timeout(LOGIN_TIMEOUT, wait_for_code(&listener, &state))
+        .await
+        .map_err(|_| {
+            anyhow!("No answer from the browser within {:?}", LOGIN_TIMEOUT)
+        })??;
+
+    #[derive(Deserialize)]
+    struct Resp {
+        refresh_token: Option<String>,
+    }
+    let resp = client
+        .post(&token_address)
+        .form(&[
+            ("code", code.as_str()),
+            ("grant_type", "authorization_code"),
+            ("client_id", app_key.as_str()),
+            ("code_verifier", verifier.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+        ])
+        .send()
+        .await
+        .context("Token request failed")?;
+    let body: Resp = check_response(resp, "Authorization code exchange")
+        .await?
+        .json()
+        .await
+        .context("Parsing token JSON")?;
+    let refresh_token =
+        body.refresh_token.context("Dropbox returned no refresh token")?;
+    if settings.parse("TOKEN_STORE", TokenStore::File)? == TokenStore::Keyring {
+        match save_keyring_refresh_token(&app_key, &refresh_token) {
+            Ok(()) => {
+                info!("Saved the refresh token in the keyring");
+                return Ok(());
+            }
+            Err(e) => warn!("Keyring unavailable ({}), using {:?} instead", e, env_file),
+        }
+    }
+    set_env_var(env_file, "REFRESH_TOKEN", &refresh_token)?;
+    info!("Saved the refresh token to {:?}", env_file);
+    Ok(())
+}
+
+fn random_string(len: usize) -> String {
+    rand::rng().sample_iter(Alphanumeric).take(len).map(char::from).collect()
+}
+
+/// Best effort: the URL has been printed either way.
+fn open_browser(url: &str) {
+    let (program, args): (&str, &[&str]) = if cfg!(target_os = "windows") {
+        ("rundll32", &["url.dll,FileProtocolHandler"])
+    } else if cfg!(target_os = "macos") {
+        ("open", &[])
+    } else {
+        ("xdg-open", &[])
+    };
+    let spawned = Command::new(program)
+        .args(args)
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if let Err(e) = spawned {
+        warn!("Could not open a browser ({}), open the URL yourself", e);
+    }
+}
+
+/// Serves requests until the redirect with `state` arrives, returning its
+/// authorization code. A denial in the browser is an error; requests for
+/// other paths or with another state are answered and ignored.
+async fn wait_for_code(listener: &TcpListener, state: &str) -> Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let Some(params) = read_callback(&mut stream).await else {
+            respond(&mut stream, "404 Not Found", "Not found").await;
+            continue;
+        };
+        if params.get("state").map(String::as_str) != Some(state) {
+            warn!("Ignoring a redirect with an unexpected state");
+            respond(&mut stream, "400 Bad Request", "Unexpected state.").await;
+            continue;
+        }
+        if let Some(error) = params.get("error") {
+            respond(
+                &mut stream,
+                "200 OK",
+                "Access was not granted. You can close this window.",
+            )
+            .await;
+            if error == "access_denied" {
+                return Err(anyhow!("Access was denied in the browser"));
+            }
+            let description =
+                params.get("error_description").map(String::as_str).unwrap_or_default();
+            return Err(anyhow!("Authorization failed: {} {}", error, description));
+        }
+        if let Some(code) = params.get("code") {
+            respond(
+                &mut stream,
+                "200 OK",
+                "fs_library is authorized. You can close this window.",
+            )
+            .await;
+            return Ok(code.clone());
+        }
+        respond(&mut stream, "400 Bad Request", "Missing code.").await;
+    }
+}
+
+/// The query parameters of a `GET /callback` request.
+async fn read_callback(stream: &mut TcpStream) -> Option<HashMap<String, String>> {
+    let mut buf = vec![0; 8192];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => len += n,
+        }
+    }
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let target = request.lines().next()?.strip_prefix("GET ")?.split(' ').next()?;
+    let url = reqwest::Url::parse(&format!("http://127.0.0.1{}", target)).ok()?;
+    (url.path() == "/callback").then(|| url.query_pairs().into_owned().collect())
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    // The browser going away does not change the outcome.
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Sets `key` in the dotenv file at `path`, replacing its line if there is one
+/// and keeping the file's permissions. The file is replaced by a rename, so it
+/// is never left half written.
+fn set_env_var(path: &Path, key: &str, value: &str) -> Result<()> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Read {:?}", path)),
+    };
+    let line = format!("{}={}", key, value);
+    let mut found = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|l| match l.split_once('=') {
+            Some((name, _)) if !found && name.trim() == key => {
+                found = true;
+                line.clone()
+            }
+            _ => l.to_string(),
+        })
+        .collect();
+    if !found {
+        lines.push(line);
+    }
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".partial");
+    let temp = path.with_file_name(temp_name);
+    fs::write(&temp, lines.join("\n") + "\n")
+        .and_then(|()| match fs::metadata(path) {
+            Ok(metadata) => fs::set_permissions(&temp, metadata.permissions()),
+            Err(_) => Ok(()),
+        })
+        .and_then(|()| fs::rename(&temp, path))
+        .with_context(|| format!("Write {:?}", path))
+}
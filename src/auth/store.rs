@@ -0,0 +1,82 @@
+//! Where tokens are kept between runs: plain files, or the OS secret store
+//! (Keychain, Credential Manager, Secret Service) with `TOKEN_STORE=keyring`.
+//!
+//! The keyring entries are named after `APP_KEY`, so several configurations
+//! using different apps do not overwrite each other's tokens. Whenever the
+//! keyring cannot be reached, the short-lived token goes to `short_token_file`
+//! instead.
+
+use anyhow::{Context, Result};
+use keyring::Entry;
+use log::warn;
+
+use super::{ShortToken, read_short_token_file, write_short_token};
+use crate::config::{Config, TokenStore};
+
+const SERVICE: &str = "fs_library";
+
+fn entry(app_key: &str, what: &str) -> keyring::Result<Entry> {
+    Entry::new(SERVICE, &format!("{}:{}", what, app_key))
+}
+
+/// The stored value, `None` if the keyring has none and an error if the keyring
+/// cannot be used. Blocks on the platform's secret store.
+fn get(app_key: &str, what: &str) -> keyring::Result<Option<String>> {
+    match entry(app_key, what)?.get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn set(app_key: &str, what: &str, value: &str) -> keyring::Result<()> {
+    entry(app_key, what)?.set_password(value)
+}
+
+pub(crate) async fn load_short_token(config: &Config) -> Result<Option<ShortToken>> {
+    if config.token_store == TokenStore::Keyring {
+        let app_key = config.app_key.clone();
+        match tokio::task::spawn_blocking(move || get(&app_key, "short_token")).await? {
+            Ok(Some(json)) => {
+                return Ok(serde_json::from_str(&json)
+                    .ok()
+                    .filter(ShortToken::is_plausible));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => warn!(
+                "Keyring unavailable ({}), reading the token from {:?}",
+                e, config.short_token_file
+            ),
+        }
+    }
+    read_short_token_file(&config.short_token_file)
+}
+
+pub(crate) async fn save_short_token(config: &Config, token: &ShortToken) -> Result<()> {
+    if config.token_store == TokenStore::Keyring {
+        let app_key = config.app_key.clone();
+        let json = serde_json::to_string(token)?;
+        match tokio::task::spawn_blocking(move || set(&app_key, "short_token", &json))
+            .await?
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!(
+                "Keyring unavailable ({}), writing the token to {:?}",
+                e, config.short_token_file
+            ),
+        }
+    }
+    write_short_token(&config.short_token_file, token).await
+}
+
+/// The refresh token `auth login` saved in the keyring for `app_key`.
+pub(crate) fn keyring_refresh_token(app_key: &str) -> Result<Option<String>> {
+    get(app_key, "refresh_token").context("Reading the refresh token from the keyring")
+}
+
+pub(crate) fn save_keyring_refresh_token(
+    app_key: &str,
+    token: &str,
+) -> keyring::Result<()> {
+    set(app_key, "refresh_token", token)
+}
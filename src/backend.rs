@@ -0,0 +1,217 @@
+//! Where uploaded files end up. Scanning, the upload log and moving files away
+//! do not depend on it; only the transfer itself goes through [`Backend`].
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::future::Future;
+use std::path::Path;
+
+use crate::auth::TokenCache;
+use crate::config::{BackendKind, Config};
+use crate::dropbox::{DropboxClient, FileMetadata};
+use crate::http::{UploadError, build_http_client, with_retries};
+use crate::upload::upload_to_dropbox;
+
+mod local;
+
+pub use local::LocalBackend;
+
+/// What gets uploaded for one local file.
+#[derive(Debug)]
+pub struct Payload<'a> {
+    /// The file being backed up, named in messages and whose modified time is
+    /// sent.
+    pub local_file: &'a Path,
+    /// The file whose bytes are sent: `local_file` itself or a compressed or
+    /// encrypted copy.
+    pub data: &'a Path,
+    /// Dropbox content hash of `data`.
+    pub content_hash: &'a str,
+    /// Dropbox content hash of `local_file`, which stays the same however
+    /// `data` was produced.
+    pub local_hash: &'a str,
+    /// Destination path, `/`-separated and starting at `dropbox_dir`.
+    pub remote_path: String,
+}
+
+/// A storage provider files can be uploaded to.
+pub trait Backend {
+    /// Stores `payload.data` at `payload.remote_path` as `upload_mode` and
+    /// `autorename` say, returning its metadata there. Transient failures are
+    /// retried and rejected credentials renewed inside.
+    fn upload(
+        &self,
+        config: &Config,
+        payload: &Payload<'_>,
+    ) -> impl Future<Output = Result<FileMetadata>> + Send;
+
+    /// Whether something is stored at `remote_path`.
+    fn exists(
+        &self,
+        config: &Config,
+        remote_path: &str,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Metadata of the file stored at `remote_path`, if any.
+    fn metadata(
+        &self,
+        config: &Config,
+        remote_path: &str,
+    ) -> impl Future<Output = Result<Option<FileMetadata>>> + Send;
+
+    /// Fails if the credentials are not accepted, so broken ones show up before
+    /// a run rather than on its first upload.
+    fn check_auth(&self, config: &Config) -> impl Future<Output = Result<()>> + Send;
+
+    /// Discards any cached credentials and obtains fresh ones now, e.g. after
+    /// they were revoked.
+    fn refresh_auth(&self, config: &Config) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Dropbox, through any [`DropboxClient`] and with the short-lived token shared
+/// by all uploads.
+#[derive(Clone)]
+pub struct DropboxBackend<C = reqwest::Client> {
+    pub client: C,
+    pub tokens: TokenCache,
+}
+
+impl<C: DropboxClient> DropboxBackend<C> {
+    pub fn new(client: C) -> Self {
+        Self { client, tokens: TokenCache::default() }
+    }
+}
+
+impl<C: DropboxClient + Sync> Backend for DropboxBackend<C> {
+    async fn upload(
+        &self,
+        config: &Config,
+        payload: &Payload<'_>,
+    ) -> Result<FileMetadata> {
+        upload_to_dropbox(&self.client, config, &self.tokens, payload).await
+    }
+
+    async fn exists(&self, config: &Config, remote_path: &str) -> Result<bool> {
+        let token = self.tokens.get(&self.client, config).await?;
+        Ok(self.client.get_metadata(config, &token, remote_path).await?.is_some())
+    }
+
+    async fn metadata(
+        &self,
+        config: &Config,
+        remote_path: &str,
+    ) -> Result<Option<FileMetadata>> {
+        let token = self.tokens.get(&self.client, config).await?;
+        self.client.get_metadata(config, &token, remote_path).await
+    }
+
+    async fn check_auth(&self, config: &Config) -> Result<()> {
+        let account = async {
+            let token = self.tokens.get(&self.client, config).await?;
+            let current_account = |token: String| async move {
+                with_retries(&config.retry, "Get current account", || {
+                    self.client.get_current_account(config, &token)
+                })
+                .await
+            };
+            match current_account(token.clone()).await {
+                // The saved short-lived token may have been revoked since.
+                Err(UploadError::Unauthorized(_)) => {
+                    warn!("Saved token rejected, refreshing...");
+                    let token =
+                        self.tokens.refresh(&self.client, config, &token).await?;
+                    current_account(token).await
+                }
+                result => result,
+            }
+        }
+        .await
+        .context(
+            "Dropbox did not accept the credentials; check APP_KEY, APP_SECRET and \
+             REFRESH_TOKEN, or run `fs_library auth login` for a new refresh token",
+        )?;
+        info!(
+            "Authenticated as {}",
+            account.email.as_deref().unwrap_or(&account.account_id)
+        );
+        Ok(())
+    }
+
+    async fn refresh_auth(&self, config: &Config) -> Result<()> {
+        let token = self.tokens.get(&self.client, config).await?;
+        self.tokens.refresh(&self.client, config, &token).await?;
+        Ok(())
+    }
+}
+
+/// The backend `BACKEND` selects.
+pub enum AnyBackend {
+    Dropbox(DropboxBackend),
+    Local(LocalBackend),
+}
+
+impl AnyBackend {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(match config.backend {
+            BackendKind::Dropbox => {
+                Self::Dropbox(DropboxBackend::new(build_http_client(config)?))
+            }
+            BackendKind::Local => Self::Local(LocalBackend::new(&config.backend_dir)),
+        })
+    }
+
+    /// [`AnyBackend::from_config`], then [`Backend::check_auth`] if `check_auth`
+    /// is set.
+    pub async fn connect(config: &Config) -> Result<Self> {
+        let backend = Self::from_config(config)?;
+        if config.check_auth {
+            backend.check_auth(config).await?;
+        }
+        Ok(backend)
+    }
+}
+
+impl Backend for AnyBackend {
+    async fn upload(
+        &self,
+        config: &Config,
+        payload: &Payload<'_>,
+    ) -> Result<FileMetadata> {
+        match self {
+            Self::Dropbox(backend) => backend.upload(config, payload).await,
+            Self::Local(backend) => backend.upload(config, payload).await,
+        }
+    }
+
+    async fn exists(&self, config: &Config, remote_path: &str) -> Result<bool> {
+        match self {
+            Self::Dropbox(backend) => backend.exists(config, remote_path).await,
+            Self::Local(backend) => backend.exists(config, remote_path).await,
+        }
+    }
+
+    async fn metadata(
+        &self,
+        config: &Config,
+        remote_path: &str,
+    ) -> Result<Option<FileMetadata>> {
+        match self {
+            Self::Dropbox(backend) => backend.metadata(config, remote_path).await,
+            Self::Local(backend) => backend.metadata(config, remote_path).await,
+        }
+    }
+
+    async fn check_auth(&self, config: &Config) -> Result<()> {
+        match self {
+            Self::Dropbox(backend) => backend.check_auth(config).await,
+            Self::Local(backend) => backend.check_auth(config).await,
+        }
+    }
+
+    async fn refresh_auth(&self, config: &Config) -> Result<()> {
+        match self {
+            Self::Dropbox(backend) => backend.refresh_auth(config).await,
+            Self::Local(backend) => backend.refresh_auth(config).await,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! A directory on a local disk or mounted NAS share standing in for Dropbox.
+
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, FileTimes, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use super::{Backend, Payload};
+use crate::config::{CollisionPolicy, Config, UploadMode};
+use crate::dropbox::FileMetadata;
+use crate::files::{content_hash, content_hash_async, free_destination};
+use crate::http::UploadError;
+
+/// Copies files to `root`, with each remote path as a relative path under it.
+///
+/// `add` mode refuses to replace an existing file unless `autorename` is set,
+/// in which case a numbered name is picked the way Dropbox would. `overwrite`
+/// and `update` both replace it, as there are no revisions to compare. Copies
+/// are renamed into place, so the target never holds a partial file.
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf() }
+    }
+
+    fn target(&self, remote_path: &str) -> PathBuf {
+        self.root.join(remote_path.trim_start_matches('/'))
+    }
+}
+
+impl Backend for LocalBackend {
+    async fn upload(
+        &self,
+        config: &Config,
+        payload: &Payload<'_>,
+    ) -> Result<FileMetadata> {
+        let mut dest = self.target(&payload.remote_path);
+        if config.upload_mode == UploadMode::Add && dest.exists() {
+            if !config.autorename {
+                return Err(
+                    UploadError::Conflict(anyhow!("{:?} already exists", dest)).into()
+                );
+            }
+            dest = free_destination(&dest, CollisionPolicy::Suffix);
+        }
+        let data = payload.data.to_path_buf();
+        let modified = if config.set_client_modified {
+            Some(fs::metadata(payload.local_file)?.modified()?)
+        } else {
+            None
+        };
+        let copied = dest.clone();
+        let hash = tokio::task::spawn_blocking(move || -> Result<String> {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut temp_name = dest.file_name().unwrap_or_default().to_os_string();
+            temp_name.push(".partial");
+            let temp = dest.with_file_name(temp_name);
+            fs::copy(&data, &temp)?;
+            let file = OpenOptions::new().write(true).open(&temp)?;
+            if let Some(modified) = modified {
+                file.set_times(FileTimes::new().set_modified(modified))?;
+            }
+            file.sync_all()?;
+            fs::rename(&temp, &dest)?;
+            content_hash(&dest)
+        })
+        .await?
+        .with_context(|| format!("Copy {:?} to {:?}", payload.data, copied))?;
+        Ok(FileMetadata { content_hash: Some(hash), rev: None })
+    }
+
+    async fn exists(&self, _config: &Config, remote_path: &str) -> Result<bool> {
+        Ok(self.target(remote_path).exists())
+    }
+
+    async fn metadata(
+        &self,
+        _config: &Config,
+        remote_path: &str,
+    ) -> Result<Option<FileMetadata>> {
+        let target = self.target(remote_path);
+        if !target.is_file() {
+            return Ok(None);
+        }
+        let hash = content_hash_async(&target).await?;
+        Ok(Some(FileMetadata { content_hash: Some(hash), rev: None }))
+    }
+
+    async fn check_auth(&self, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+
+    async fn refresh_auth(&self, _config: &Config) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,390 @@
+//! The Dropbox API calls the uploader makes, behind [`DropboxClient`] so the
+//! upload logic does not depend on `reqwest` directly.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use futures::stream;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use tokio::fs as tokio_fs;
+use tokio_util::io::ReaderStream;
+
+use crate::auth::ShortToken;
+use crate::config::Config;
+use crate::http::{check_response, http_status_error};
+use crate::throttle::{PIECE_SIZE, throttled};
+
+/// The only body type the content upload endpoints accept. Dropbox does not
+/// store a MIME type for a file; previews and integrations go by the extension
+/// of its name, so there is nothing to gain from guessing one here.
+const UPLOAD_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The subset of Dropbox `FileMetadata` returned by a finished upload that we
+/// act on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileMetadata {
+    pub content_hash: Option<String>,
+    pub rev: Option<String>,
+}
+
+/// What `files/save_url` returns: usually a job to poll, or the file right away.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum SaveUrlJob {
+    AsyncJobId { async_job_id: String },
+    Complete(FileMetadata),
+}
+
+/// State of a `files/save_url` job, from `files/save_url/check_job_status`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = ".tag", rename_all = "snake_case")]
+pub enum SaveUrlStatus {
+    InProgress,
+    Complete(FileMetadata),
+    /// With Dropbox's reason, such as `download_failed`.
+    Failed {
+        failed: serde_json::Value,
+    },
+}
+
+/// The account the credentials belong to, from `users/get_current_account`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Account {
+    pub account_id: String,
+    pub email: Option<String>,
+}
+
+/// One method per Dropbox endpoint. Each call is a single attempt; retries,
+/// token refreshes and resuming are left to the caller.
+///
+/// Endpoint addresses and credentials come from the `Config` passed in, and
+/// `short_token` is the bearer token for the call.
+pub trait DropboxClient {
+    /// `oauth2/token`: exchanges the configured refresh token for a new
+    /// short-lived access token.
+    fn refresh_token(
+        &self,
+        config: &Config,
+    ) -> impl Future<Output = Result<ShortToken>> + Send;
+
+    /// `users/get_current_account`, the cheapest call that needs a valid token.
+    fn get_current_account(
+        &self,
+        config: &Config,
+        short_token: &str,
+    ) -> impl Future<Output = Result<Account>> + Send;
+
+    /// `files/get_metadata`, returning `None` if `path` does not exist.
+    fn get_metadata(
+        &self,
+        config: &Config,
+        short_token: &str,
+        path: &str,
+    ) -> impl Future<Output = Result<Option<FileMetadata>>> + Send;
+
+    /// `files/upload` of the whole of `local_file`, `size` bytes long, in one
+    /// request.
+    fn upload(
+        &self,
+        config: &Config,
+        short_token: &str,
+        commit: &serde_json::Value,
+        local_file: &Path,
+        size: u64,
+    ) -> impl Future<Output = Result<FileMetadata>> + Send;
+
+    /// `files/upload_session/start`, returning the new session id.
+    fn start_upload_session(
+        &self,
+        config: &Config,
+        short_token: &str,
+    ) -> impl Future<Output = Result<String>> + Send;
+
+    /// `files/upload_session/append_v2` of `chunk` at `offset`.
+    fn append_upload_session(
+        &self,
+        config: &Config,
+        short_token: &str,
+        session_id: &str,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// `files/upload_session/finish`, committing the `offset` bytes uploaded so
+    /// far as described by `commit`.
+    fn finish_upload_session(
+        &self,
+        config: &Config,
+        short_token: &str,
+        session_id: &str,
+        offset: u64,
+        commit: &serde_json::Value,
+    ) -> impl Future<Output = Result<FileMetadata>> + Send;
+
+    /// `files/save_url`: has Dropbox download `url` to `path` itself.
+    fn save_url(
+        &self,
+        config: &Config,
+        short_token: &str,
+        path: &str,
+        url: &str,
+    ) -> impl Future<Output = Result<SaveUrlJob>> + Send;
+
+    /// `files/save_url/check_job_status` of a job `save_url` started.
+    fn check_save_url_job(
+        &self,
+        config: &Config,
+        short_token: &str,
+        async_job_id: &str,
+    ) -> impl Future<Output = Result<SaveUrlStatus>> + Send;
+}
+
+impl DropboxClient for reqwest::Client {
+    async fn refresh_token(&self, config: &Config) -> Result<ShortToken> {
+        #[derive(Deserialize)]
+        struct Resp {
+            access_token: String,
+            expires_in: Option<i64>,
+        }
+
+        let resp = self
+            .post(&config.api_refresh_address)
+            .form(&[
+                ("refresh_token", config.refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+                ("client_id", config.app_key.as_str()),
+                ("client_secret", config.app_secret.as_str()),
+            ])
+            .send()
+            .await
+            .context("Token refresh request failed")?;
+        let body: Resp = check_response(resp, "Token refresh")
+            .await?
+            .json()
+            .await
+            .context("Parsing token refresh JSON")?;
+        Ok(ShortToken {
+            access_token: body.access_token,
+            expires_at: body
+                .expires_in
+                .map(|secs| Utc::now() + chrono::Duration::seconds(secs)),
+        })
+    }
+
+    async fn get_current_account(
+        &self,
+        config: &Config,
+        short_token: &str,
+    ) -> Result<Account> {
+        // The endpoint takes no arguments and wants a `null` body.
+        let resp = api_post(self, config, &config.api_account_address, short_token)
+            .json(&serde_json::Value::Null)
+            .send()
+            .await?;
+        check_response(resp, "Get current account")
+            .await?
+            .json()
+            .await
+            .context("Parsing account JSON")
+    }
+
+    async fn get_metadata(
+        &self,
+        config: &Config,
+        short_token: &str,
+        path: &str,
+    ) -> Result<Option<FileMetadata>> {
+        let resp = api_post(self, config, &config.api_metadata_address, short_token)
+            .json(&serde_json::json!({ "path": path }))
+            .send()
+            .await?;
+        match check_response(resp, "Get metadata").await {
+            Ok(resp) => Ok(Some(resp.json().await.context("Parsing metadata JSON")?)),
+            Err(e)
+                if http_status_error(&e).is_some_and(|e| {
+                    e.status == StatusCode::CONFLICT
+                        && e.api_error.as_ref().is_some_and(|e| {
+                            e.tag() == Some("path")
+                                && e.inner_tag("path") == Some("not_found")
+                        })
+                }) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn upload(
+        &self,
+        config: &Config,
+        short_token: &str,
+        commit: &serde_json::Value,
+        local_file: &Path,
+        size: u64,
+    ) -> Result<FileMetadata> {
+        let file = tokio_fs::File::open(local_file).await?;
+        let body = reqwest::Body::wrap_stream(throttled(
+            ReaderStream::with_capacity(file, PIECE_SIZE),
+            config.upload_rate_limit.clone(),
+        ));
+
+        let req = api_post(self, config, &config.api_address, short_token)
+            .header("Content-Type", UPLOAD_CONTENT_TYPE)
+            .header("Content-Length", size)
+            .header("Dropbox-API-Arg", commit.to_string())
+            .timeout(config.upload_timeout)
+            .body(body);
+
+        let resp = check_response(req.send().await?, "Upload").await?;
+        resp.json().await.context("Parsing upload response JSON")
+    }
+
+    async fn start_upload_session(
+        &self,
+        config: &Config,
+        short_token: &str,
+    ) -> Result<String> {
+        #[derive(Deserialize)]
+        struct StartResp {
+            session_id: String,
+        }
+
+        let arg = serde_json::json!({ "close": false });
+        let StartResp { session_id } =
+            upload_session_call(self, config, short_token, "start", arg, Vec::new())
+                .await?
+                .json()
+                .await
+                .context("Parsing upload session start JSON")?;
+        Ok(session_id)
+    }
+
+    async fn append_upload_session(
+        &self,
+        config: &Config,
+        short_token: &str,
+        session_id: &str,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<()> {
+        let arg = serde_json::json!({
+            "cursor": { "session_id": session_id, "offset": offset },
+            "close": false,
+        });
+        upload_session_call(self, config, short_token, "append_v2", arg, chunk).await?;
+        Ok(())
+    }
+
+    async fn finish_upload_session(
+        &self,
+        config: &Config,
+        short_token: &str,
+        session_id: &str,
+        offset: u64,
+        commit: &serde_json::Value,
+    ) -> Result<FileMetadata> {
+        let arg = serde_json::json!({
+            "cursor": { "session_id": session_id, "offset": offset },
+            "commit": commit,
+        });
+        upload_session_call(self, config, short_token, "finish", arg, Vec::new())
+            .await?
+            .json()
+            .await
+            .context("Parsing upload session finish JSON")
+    }
+
+    async fn save_url(
+        &self,
+        config: &Config,
+        short_token: &str,
+        path: &str,
+        url: &str,
+    ) -> Result<SaveUrlJob> {
+        let resp = api_post(self, config, &config.api_save_url_address, short_token)
+            .json(&serde_json::json!({ "path": path, "url": url }))
+            .send()
+            .await?;
+        check_response(resp, "Save URL")
+            .await?
+            .json()
+            .await
+            .context("Parsing save URL JSON")
+    }
+
+    async fn check_save_url_job(
+        &self,
+        config: &Config,
+        short_token: &str,
+        async_job_id: &str,
+    ) -> Result<SaveUrlStatus> {
+        let url = format!("{}/check_job_status", config.api_save_url_address);
+        let resp = api_post(self, config, &url, short_token)
+            .json(&serde_json::json!({ "async_job_id": async_job_id }))
+            .send()
+            .await?;
+        check_response(resp, "Check save URL job")
+            .await?
+            .json()
+            .await
+            .context("Parsing save URL job status JSON")
+    }
+}
+
+/// A POST to the API endpoint at `url` with `short_token`, in the team space
+/// `dropbox_namespace_id` names if set.
+fn api_post(
+    client: &reqwest::Client,
+    config: &Config,
+    url: &str,
+    short_token: &str,
+) -> reqwest::RequestBuilder {
+    let req =
+        client.post(url).header("Authorization", format!("Bearer {}", short_token));
+    match &config.dropbox_namespace_id {
+        Some(id) => req.header(
+            "Dropbox-API-Path-Root",
+            serde_json::json!({ ".tag": "namespace_id", "namespace_id": id })
+                .to_string(),
+        ),
+        None => req,
+    }
+}
+
+async fn upload_session_call(
+    client: &reqwest::Client,
+    config: &Config,
+    short_token: &str,
+    endpoint: &str,
+    arg: serde_json::Value,
+    body: Vec<u8>,
+) -> Result<reqwest::Response> {
+    let len = body.len();
+    let timeout =
+        if body.is_empty() { config.request_timeout } else { config.upload_timeout };
+    let body = match &config.upload_rate_limit {
+        Some(limiter) => {
+            let pieces: Vec<io::Result<Vec<u8>>> =
+                body.chunks(PIECE_SIZE).map(|piece| Ok(piece.to_vec())).collect();
+            reqwest::Body::wrap_stream(throttled(
+                stream::iter(pieces),
+                Some(limiter.clone()),
+            ))
+        }
+        None => reqwest::Body::from(body),
+    };
+    let url = format!("{}/{}", config.api_upload_session_address, endpoint);
+    let resp = api_post(client, config, &url, short_token)
+        .header("Content-Type", UPLOAD_CONTENT_TYPE)
+        .header("Content-Length", len)
+        .header("Dropbox-API-Arg", arg.to_string())
+        .timeout(timeout)
+        .body(body)
+        .send()
+        .await?;
+    check_response(resp, &format!("Upload session {}", endpoint)).await
+}
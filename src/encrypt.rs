@@ -0,0 +1,206 @@
+//! Client-side AES-256-GCM encryption of uploaded files.
+//!
+//! An encrypted file is a random 7-byte nonce prefix followed by the plaintext
+//! in segments of [`SEGMENT_SIZE`] bytes, each sealed with its own 16-byte tag
+//! using the STREAM construction. The last segment is sealed with a distinct
+//! nonce flag, so a truncated file fails to decrypt instead of decrypting to a
+//! prefix of the original.
+
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, generic_array::GenericArray};
+use aes_gcm::{Aes256Gcm, aead};
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::config::EncryptionKey;
+
+/// Plaintext bytes per sealed segment.
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 7;
+
+fn cipher(key: &EncryptionKey) -> Aes256Gcm {
+    Aes256Gcm::new(GenericArray::from_slice(&key.0))
+}
+
+fn sealing_error(_: aead::Error) -> io::Error {
+    io::Error::other("AES-GCM encryption failed")
+}
+
+/// Encrypts everything written to it into `inner`. [`EncryptWriter::finish`]
+/// must be called to seal the last segment.
+pub(crate) struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+    segment: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Writes a fresh random nonce prefix to `inner`.
+    pub(crate) fn new(key: &EncryptionKey, mut inner: W) -> io::Result<Self> {
+        let nonce: [u8; NONCE_PREFIX_SIZE] = rand::random();
+        inner.write_all(&nonce)?;
+        Ok(Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(
+                cipher(key),
+                GenericArray::from_slice(&nonce),
+            )),
+            segment: Vec::with_capacity(SEGMENT_SIZE + TAG_SIZE),
+        })
+    }
+
+    /// Seals the buffered tail as the last segment and returns `inner`.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let encryptor = self.encryptor.take().expect("finish called once");
+        encryptor
+            .encrypt_last_in_place(b"", &mut self.segment)
+            .map_err(sealing_error)?;
+        self.inner.write_all(&self.segment)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full segment is only sealed once more data arrives, because the
+        // last one has to be sealed differently.
+        if self.segment.len() == SEGMENT_SIZE && !buf.is_empty() {
+            let encryptor = self.encryptor.as_mut().expect("write after finish");
+            encryptor
+                .encrypt_next_in_place(b"", &mut self.segment)
+                .map_err(sealing_error)?;
+            self.inner.write_all(&self.segment)?;
+            self.segment.clear();
+        }
+        let n = buf.len().min(SEGMENT_SIZE - self.segment.len());
+        self.segment.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads until `buf` is full or the input ends, returning the bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Decrypts `input`, a file uploaded with encryption enabled, into `output`.
+/// The plaintext is written under a temporary name and only renamed into place
+/// once every segment has authenticated.
+pub fn decrypt_file(key: &EncryptionKey, input: &Path, output: &Path) -> Result<()> {
+    let mut reader = BufReader::new(
+        File::open(input).with_context(|| format!("Open {:?} for decryption", input))?,
+    );
+    let mut nonce = [0u8; NONCE_PREFIX_SIZE];
+    if read_full(&mut reader, &mut nonce)? < NONCE_PREFIX_SIZE {
+        return Err(anyhow!("{:?} is too short to be an encrypted file", input));
+    }
+    let mut decryptor =
+        DecryptorBE32::from_aead(cipher(key), GenericArray::from_slice(&nonce));
+
+    let mut temp_name = output.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".partial");
+    let temp = output.with_file_name(temp_name);
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    let result = (|| -> Result<()> {
+        let mut current = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+        let mut next = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+        let mut len = read_full(&mut reader, &mut current)?;
+        loop {
+            let next_len = read_full(&mut reader, &mut next)?;
+            current.truncate(len);
+            if next_len == 0 {
+                decryptor.decrypt_last_in_place(b"", &mut current).map_err(|_| {
+                    anyhow!("Decrypting {:?}: wrong key or corrupt file", input)
+                })?;
+                writer.write_all(&current)?;
+                break;
+            }
+            decryptor.decrypt_next_in_place(b"", &mut current).map_err(|_| {
+                anyhow!("Decrypting {:?}: wrong key or corrupt file", input)
+            })?;
+            writer.write_all(&current)?;
+            std::mem::swap(&mut current, &mut next);
+            next.resize(SEGMENT_SIZE + TAG_SIZE, 0);
+            len = next_len;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => fs::rename(&temp, output)
+            .with_context(|| format!("Write decrypted file {:?}", output)),
+        Err(e) => {
+            fs::remove_file(&temp).ok();
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn encrypt(key: &EncryptionKey, plaintext: &[u8], output: &Path) {
+        let mut writer = EncryptWriter::new(key, Vec::new()).unwrap();
+        writer.write_all(plaintext).unwrap();
+        fs::write(output, writer.finish().unwrap()).unwrap();
+    }
+
+    fn plaintext() -> Vec<u8> {
+        // Several segments and a partial last one.
+        (0..3 * SEGMENT_SIZE + 100).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new();
+        let key = EncryptionKey([7; 32]);
+        let (sealed, opened) = (dir.path().join("sealed"), dir.path().join("opened"));
+        for plaintext in [Vec::new(), vec![1; SEGMENT_SIZE], plaintext()] {
+            encrypt(&key, &plaintext, &sealed);
+            decrypt_file(&key, &sealed, &opened).unwrap();
+            assert_eq!(fs::read(&opened).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let dir = TempDir::new();
+        let key = EncryptionKey([7; 32]);
+        let (sealed, opened) = (dir.path().join("sealed"), dir.path().join("opened"));
+        encrypt(&key, &plaintext(), &sealed);
+        // Cut at a segment boundary, so what is left looks complete but was
+        // not sealed as the last segment.
+        let bytes = fs::read(&sealed).unwrap();
+        fs::write(&sealed, &bytes[..NONCE_PREFIX_SIZE + 2 * (SEGMENT_SIZE + TAG_SIZE)])
+            .unwrap();
+        assert!(decrypt_file(&key, &sealed, &opened).is_err());
+        assert!(!opened.exists());
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let dir = TempDir::new();
+        let (sealed, opened) = (dir.path().join("sealed"), dir.path().join("opened"));
+        encrypt(&EncryptionKey([7; 32]), &plaintext(), &sealed);
+        assert!(decrypt_file(&EncryptionKey([8; 32]), &sealed, &opened).is_err());
+        assert!(!opened.exists());
+    }
+}
@@ -0,0 +1,293 @@
+//! Local file operations: hashing, renaming and moving uploaded files away.
+
+use anyhow::{Context, Result, anyhow};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::config::CollisionPolicy;
+
+/// Block size Dropbox uses when computing a file's `content_hash`. It is part
+/// of the hash's definition, so unlike `upload_chunk_size` it cannot be tuned
+/// without the hashes no longer matching the ones Dropbox reports.
+pub(crate) const CONTENT_HASH_BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Bytes read from each end of a file for its [`quick_hash`].
+const QUICK_HASH_SAMPLE_SIZE: u64 = 1024 * 1024;
+
+pub(crate) fn extract_filename(path: &Path) -> Result<String> {
+    let name = path.file_name().ok_or_else(|| anyhow!("No filename in path"))?;
+    Ok(name.to_str().ok_or_else(not_utf8)?.to_string())
+}
+
+/// `path` as a string for Dropbox paths and the upload log, which are UTF-8.
+/// Names in other encodings are refused rather than converted lossily, which
+/// would store the file under a name that no longer matches it.
+pub(crate) fn utf8_path(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(not_utf8)
+}
+
+pub(crate) fn not_utf8() -> anyhow::Error {
+    anyhow!(
+        "The path is not valid UTF-8, which Dropbox requires; rename the file to back it up"
+    )
+}
+
+/// Moves `source` into `destination_dir`. With `expected_hash` the moved file is
+/// hashed again and must have that content hash; when the move had to copy
+/// across filesystems this is checked before `source` is deleted, so a bad copy
+/// leaves `source` where it was.
+pub(crate) fn move_file(
+    source: &Path,
+    destination_dir: &Path,
+    collision: CollisionPolicy,
+    expected_hash: Option<&str>,
+) -> Result<PathBuf> {
+    fs::create_dir_all(destination_dir)?;
+    let dest = free_destination(
+        &destination_dir.join(source.file_name().ok_or_else(|| anyhow!("No filename"))?),
+        collision,
+    );
+    match fs::rename(source, &dest) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_then_remove(source, &dest, expected_hash)
+        }
+        Err(e) => Err(e.into()),
+        Ok(()) => expected_hash.map_or(Ok(()), |expected| check_hash(&dest, expected)),
+    }
+    .with_context(|| format!("Failed to move {:?} to {:?}", source, dest))?;
+    info!(path:% = source.display(); "Moved {:?} -> {:?}", source, dest);
+    Ok(dest)
+}
+
+fn check_hash(path: &Path, expected: &str) -> Result<()> {
+    let actual = content_hash(path)?;
+    if actual != expected {
+        return Err(anyhow!(
+            "{:?} has content hash {} instead of {} after the move",
+            path,
+            actual,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// Picks the path a file should be moved to when `dest` may already exist.
+pub(crate) fn free_destination(dest: &Path, collision: CollisionPolicy) -> PathBuf {
+    if collision == CollisionPolicy::Overwrite || !dest.exists() {
+        return dest.to_path_buf();
+    }
+    let stem = dest.file_stem().unwrap_or_default();
+    let ext = dest.extension();
+    // Built as OS strings so names that are not UTF-8 keep their bytes.
+    let name = |base: &OsStr, suffix: &str| {
+        let mut name = base.to_os_string();
+        name.push(suffix);
+        if let Some(ext) = ext {
+            name.push(".");
+            name.push(ext);
+        }
+        dest.with_file_name(name)
+    };
+    let base = match collision {
+        CollisionPolicy::Timestamp => {
+            let mut stamped = stem.to_os_string();
+            stamped.push(chrono::Local::now().format("_%Y%m%d-%H%M%S").to_string());
+            let candidate = name(&stamped, "");
+            if !candidate.exists() {
+                return candidate;
+            }
+            stamped
+        }
+        _ => stem.to_os_string(),
+    };
+    (1..)
+        .map(|n| name(&base, &format!("_{}", n)))
+        .find(|candidate| !candidate.exists())
+        .expect("unbounded suffix search")
+}
+
+/// Fallback for moves across filesystems: copies `source` next to `dest` under
+/// a temporary name, carries over its timestamps, checks `expected_hash` if
+/// given, renames it into place and only then deletes `source`, so `dest` never
+/// holds a partial file.
+fn copy_then_remove(
+    source: &Path,
+    dest: &Path,
+    expected_hash: Option<&str>,
+) -> Result<()> {
+    let mut temp_name = dest.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".partial");
+    let temp = dest.with_file_name(temp_name);
+
+    let metadata = fs::metadata(source)?;
+    fs::copy(source, &temp)?;
+    let copied = OpenOptions::new().write(true).open(&temp)?;
+    copied.set_times(
+        FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )?;
+    copied.sync_all()?;
+    if let Some(expected) = expected_hash
+        && let Err(e) = check_hash(&temp, expected)
+    {
+        fs::remove_file(&temp).ok();
+        return Err(e);
+    }
+    fs::rename(&temp, dest)?;
+    Ok(fs::remove_file(source)?)
+}
+
+/// Computes the Dropbox `content_hash` of a file: the SHA-256 of the
+/// concatenated SHA-256 digests of each 4 MiB block, hex encoded.
+pub(crate) fn content_hash(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Open {:?} for hashing", path))?;
+    let mut overall = Sha256::new();
+    let mut block = Vec::with_capacity(CONTENT_HASH_BLOCK_SIZE as usize);
+    loop {
+        block.clear();
+        (&mut file).take(CONTENT_HASH_BLOCK_SIZE).read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        overall.update(Sha256::digest(&block));
+    }
+    Ok(format!("{:x}", overall.finalize()))
+}
+
+pub(crate) async fn content_hash_async(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || content_hash(&path)).await?
+}
+
+/// A cheap first look at a file next to [`content_hash`]: SHA-256 over the
+/// file's size and modified time and its first and last
+/// `QUICK_HASH_SAMPLE_SIZE` bytes, so at most 2 MiB are read however large it
+/// is. A rewritten middle that keeps size and modified time goes unnoticed, so
+/// a matching quick hash is only a hint, never proof the file is unchanged.
+pub(crate) fn quick_hash(path: &Path) -> Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("Open {:?} for hashing", path))?;
+    let metadata = file.metadata()?;
+    let size = metadata.len();
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+    hasher.update(modified.as_nanos().to_le_bytes());
+    let mut sample = Vec::with_capacity(QUICK_HASH_SAMPLE_SIZE as usize);
+    (&mut file).take(QUICK_HASH_SAMPLE_SIZE).read_to_end(&mut sample)?;
+    hasher.update(&sample);
+    if size > QUICK_HASH_SAMPLE_SIZE {
+        // Not overlapping the first sample for files under twice its size.
+        let tail =
+            size.saturating_sub(QUICK_HASH_SAMPLE_SIZE).max(QUICK_HASH_SAMPLE_SIZE);
+        file.seek(SeekFrom::Start(tail))?;
+        sample.clear();
+        file.take(QUICK_HASH_SAMPLE_SIZE).read_to_end(&mut sample)?;
+        hasher.update(&sample);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub(crate) async fn quick_hash_async(path: &Path) -> Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || quick_hash(&path)).await?
+}
+
+/// The path `path` would have after replacing spaces in its file name. Names
+/// that are not UTF-8 are left alone, as they cannot be uploaded anyway.
+pub(crate) fn sanitized_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| anyhow!("No file name"))?;
+    let Some(file_name) = file_name.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+    let new_name = file_name.replace(' ', "_");
+    Ok(path.parent().unwrap_or_else(|| Path::new("")).join(new_name))
+}
+
+pub(crate) fn sanitize_filename_spaces(path: &Path) -> Result<PathBuf> {
+    let new_path = sanitized_path(path)?;
+    if new_path == path {
+        return Ok(new_path);
+    }
+    fs::rename(path, &new_path)?;
+    info!(path:% = path.display(); "Renamed file: {:?} -> {:?}", path, new_path);
+    Ok(new_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::time::SystemTime;
+
+    fn hash_of(dir: &TempDir, name: &str, bytes: &[u8]) -> String {
+        let path = dir.path().join(name);
+        fs::write(&path, bytes).unwrap();
+        content_hash(&path).unwrap()
+    }
+
+    // Worked out independently from the algorithm Dropbox documents: SHA-256
+    // over the SHA-256 digests of each 4 MiB block, with no block for an empty
+    // file or after a final full one.
+    #[test]
+    fn content_hash_known_vectors() {
+        let dir = TempDir::new();
+        let block = vec![0u8; CONTENT_HASH_BLOCK_SIZE as usize];
+        let mut over = block.clone();
+        over.push(0);
+        assert_eq!(
+            hash_of(&dir, "empty", b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hash_of(&dir, "hello", b"hello"),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+        assert_eq!(
+            hash_of(&dir, "block", &block),
+            "c7e946d101855255d919ef0c70718633adf77d3dfb3adeeecf5d0cb4e951be58"
+        );
+        assert_eq!(
+            hash_of(&dir, "over", &over),
+            "14a4d47f23a30177885d9820122f17d2d3a55fe63f7f5c27b95f689e0b2accd6"
+        );
+    }
+
+    #[test]
+    fn quick_hash_sees_the_ends_but_not_the_middle() {
+        let dir = TempDir::new();
+        let path = dir.path().join("big");
+        let sample = QUICK_HASH_SAMPLE_SIZE as usize;
+        let mut bytes = vec![0u8; 3 * sample];
+        let modified = SystemTime::now();
+        let quick = |bytes: &[u8]| {
+            fs::write(&path, bytes).unwrap();
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+            quick_hash(&path).unwrap()
+        };
+        let original = quick(&bytes);
+
+        bytes[sample + 1] = 1;
+        assert_eq!(quick(&bytes), original);
+        bytes[0] = 1;
+        let head = quick(&bytes);
+        assert_ne!(head, original);
+        *bytes.last_mut().unwrap() = 1;
+        assert_ne!(quick(&bytes), head);
+        bytes.push(0);
+        assert_ne!(quick(&bytes[..3 * sample]), quick(&bytes));
+    }
+}
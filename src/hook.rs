@@ -0,0 +1,56 @@
+//! Running `POST_UPLOAD_COMMAND` after each upload.
+
+use log::{debug, warn};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+use crate::config::Config;
+
+/// Runs `post_upload_command`, if set, for `source` once it is uploaded to
+/// `remote_path` and dealt with as `post_upload_action` says; `local_path` is
+/// where it is now, or was if it got deleted.
+///
+/// The command is started directly rather than through a shell, so paths are
+/// never interpreted. `local_path` and `remote_path` are appended as its last
+/// two arguments and are also in `FS_LIBRARY_LOCAL_PATH` and
+/// `FS_LIBRARY_REMOTE_PATH`, with `source` in `FS_LIBRARY_SOURCE_PATH`. It
+/// failing is logged without failing the upload, which is already done.
+pub(crate) async fn post_upload(
+    config: &Config,
+    source: &Path,
+    local_path: &Path,
+    remote_path: &str,
+) {
+    let Some((program, args)) =
+        config.post_upload_command.as_deref().and_then(<[_]>::split_first)
+    else {
+        return;
+    };
+    let output = Command::new(program)
+        .args(args)
+        .arg(local_path)
+        .arg(remote_path)
+        .env("FS_LIBRARY_LOCAL_PATH", local_path)
+        .env("FS_LIBRARY_REMOTE_PATH", remote_path)
+        .env("FS_LIBRARY_SOURCE_PATH", source)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {
+            debug!(path:% = source.display(); "Post-upload command succeeded");
+        }
+        Ok(output) => warn!(
+            path:% = source.display();
+            "Post-upload command {:?} failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!(
+            path:% = source.display();
+            "Could not run post-upload command {:?}: {}", program, e
+        ),
+    }
+}
@@ -0,0 +1,414 @@
+//! The shared HTTP client, status error handling and retries.
+
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use reqwest::StatusCode;
+use reqwest::header::HeaderValue;
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::config::{Config, ProxySettings, RetryPolicy};
+use crate::metrics;
+
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Error for a Dropbox API call that completed with a non-success status.
+/// Shown with the error's tag and summary when the body is Dropbox's error
+/// envelope, and with the raw body otherwise.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub what: String,
+    pub status: StatusCode,
+    pub body: String,
+    /// `body` parsed, if it is Dropbox's error envelope.
+    pub api_error: Option<DropboxApiError>,
+    /// Parsed `Retry-After` header of a 429 response, if present.
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: HTTP {}", self.what, self.status)?;
+        match &self.api_error {
+            Some(api_error) => match api_error.tag() {
+                Some(tag) => write!(f, " ({}): {}", tag, api_error.error_summary),
+                None => write!(f, ": {}", api_error.error_summary),
+            },
+            None => write!(f, " - {}", self.body),
+        }
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+impl HttpStatusError {
+    /// Why Dropbox refused a write to the path: the tag under `path` of a 409,
+    /// found under `reason` for uploads and under `path` for finished upload
+    /// sessions.
+    fn write_error(&self) -> Option<&str> {
+        if self.status != StatusCode::CONFLICT {
+            return None;
+        }
+        let e = self.api_error.as_ref().filter(|e| e.tag() == Some("path"))?;
+        e.inner_tag("reason").or_else(|| e.inner_tag("path"))
+    }
+
+    /// Whether this is Dropbox refusing a write because something is already
+    /// at the path.
+    pub fn is_path_conflict(&self) -> bool {
+        self.write_error() == Some("conflict")
+    }
+
+    /// Whether this is Dropbox refusing a write because the account is out of
+    /// space.
+    pub fn is_insufficient_space(&self) -> bool {
+        self.write_error() == Some("insufficient_space")
+    }
+}
+
+/// The body Dropbox sends with most failed API calls.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DropboxApiError {
+    /// The error's tags joined by `/`, e.g. `path/conflict/file/..`. Meant for
+    /// people; code should go by the tags in `error`.
+    pub error_summary: String,
+    #[serde(default)]
+    pub error: serde_json::Value,
+}
+
+impl DropboxApiError {
+    /// The error's `.tag`, e.g. `path` or `incorrect_offset`.
+    pub fn tag(&self) -> Option<&str> {
+        self.error[".tag"].as_str()
+    }
+
+    /// The `.tag` of the union under `field`, e.g. `conflict` under `reason`.
+    pub fn inner_tag(&self, field: &str) -> Option<&str> {
+        self.error[field][".tag"].as_str()
+    }
+}
+
+/// The [`HttpStatusError`] in `err`'s chain, looking inside an [`UploadError`]
+/// too, whose variants only pass on the source of the error they wrap.
+pub fn http_status_error(err: &anyhow::Error) -> Option<&HttpStatusError> {
+    err.chain().find_map(|cause| {
+        cause.downcast_ref::<HttpStatusError>().or_else(|| {
+            cause
+                .downcast_ref::<UploadError>()
+                .and_then(|e| http_status_error(e.error()))
+        })
+    })
+}
+
+/// Whether `err` is a write refused because the destination is taken, by
+/// Dropbox or by another backend.
+pub fn is_conflict(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        matches!(cause.downcast_ref::<UploadError>(), Some(UploadError::Conflict(_)))
+    }) || http_status_error(err).is_some_and(HttpStatusError::is_path_conflict)
+}
+
+/// Whether `err` is Dropbox refusing a write because the account is full, which
+/// every further upload would run into as well.
+pub fn is_insufficient_space(err: &anyhow::Error) -> bool {
+    http_status_error(err).is_some_and(HttpStatusError::is_insufficient_space)
+}
+
+/// A failed API call or upload, by what can be done about it. Converting an
+/// `anyhow::Error` classifies it by the HTTP status or connection error in its
+/// chain, and keeps it as the variant's message and source.
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    /// The access token was rejected (401); a fresh one may be accepted.
+    #[error(transparent)]
+    Unauthorized(anyhow::Error),
+    /// Throttled (429), with the `Retry-After` delay if Dropbox sent one.
+    #[error("{error}")]
+    RateLimited { retry_after: Option<Duration>, error: anyhow::Error },
+    /// A server-side (5xx) failure, or a connection that failed or timed out.
+    #[error(transparent)]
+    Transient(anyhow::Error),
+    /// Other client errors (4xx) and local I/O failures, which another attempt
+    /// would only repeat.
+    #[error(transparent)]
+    Permanent(anyhow::Error),
+    /// What Dropbox stored does not match the content hash of what was sent.
+    #[error(transparent)]
+    Integrity(anyhow::Error),
+    /// Something else is already at the destination, and `upload_mode` and
+    /// `autorename` say not to replace or rename around it.
+    #[error(transparent)]
+    Conflict(anyhow::Error),
+}
+
+impl UploadError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::RateLimited { .. } | Self::Transient(_))
+    }
+
+    /// The error that was classified.
+    pub fn error(&self) -> &anyhow::Error {
+        match self {
+            Self::Unauthorized(error)
+            | Self::RateLimited { error, .. }
+            | Self::Transient(error)
+            | Self::Permanent(error)
+            | Self::Integrity(error)
+            | Self::Conflict(error) => error,
+        }
+    }
+}
+
+impl From<anyhow::Error> for UploadError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<UploadError>() {
+            Ok(classified) => return classified,
+            Err(error) => error,
+        };
+        if let Some(e) = http_status_error(&error) {
+            return match e.status {
+                StatusCode::UNAUTHORIZED => Self::Unauthorized(error),
+                StatusCode::TOO_MANY_REQUESTS => {
+                    Self::RateLimited { retry_after: e.retry_after, error }
+                }
+                StatusCode::CONFLICT if e.is_path_conflict() => Self::Conflict(error),
+                status if status.is_server_error() => Self::Transient(error),
+                _ => Self::Permanent(error),
+            };
+        }
+        let connection_error = error.chain().any(|cause| {
+            cause.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+            })
+        });
+        if connection_error { Self::Transient(error) } else { Self::Permanent(error) }
+    }
+}
+
+/// Runs `op` until it succeeds, fails with an error that is not
+/// [retryable](UploadError::is_retryable), or `policy` runs out of retries.
+pub(crate) async fn with_retries<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut op: F,
+) -> Result<T, UploadError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Into<UploadError>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await.map_err(Into::into) {
+            Err(e) if attempt < policy.max_retries && e.is_retryable() => {
+                let delay = match e {
+                    UploadError::RateLimited { retry_after: Some(delay), .. } => delay,
+                    _ => policy.delay(attempt),
+                };
+                attempt += 1;
+                metrics::retried();
+                warn!(
+                    "{} failed ({}), retrying in {:?} (attempt {}/{})",
+                    what, e, delay, attempt, policy.max_retries
+                );
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub(crate) async fn check_response(
+    resp: reqwest::Response,
+    what: &str,
+) -> Result<reqwest::Response> {
+    match resp.status() {
+        s if s.is_success() => Ok(resp),
+        status => {
+            let retry_after = if status == StatusCode::TOO_MANY_REQUESTS {
+                resp.headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok())
+                    .map(Duration::from_secs)
+            } else {
+                None
+            };
+            let body = resp.text().await.unwrap_or_default();
+            let api_error = serde_json::from_str(&body).ok();
+            Err(HttpStatusError {
+                what: what.to_string(),
+                status,
+                body,
+                api_error,
+                retry_after,
+            }
+            .into())
+        }
+    }
+}
+
+/// Builds the HTTP client shared by every token refresh and upload in a run, so
+/// connections and TLS sessions are pooled instead of re-established per file.
+///
+/// Every request is bounded by `request_timeout`, so a dead connection fails
+/// (and is retried) instead of hanging the run; uploads override it with
+/// `upload_timeout`.
+///
+/// Proxies are exactly those in `config.proxy`: reqwest's own detection of the
+/// proxy env vars is turned off, so the same settings layers as everywhere else
+/// apply and a config file can set them too. The scheme-specific proxies take
+/// precedence over `all`.
+pub fn build_http_client(config: &Config) -> Result<reqwest::Client> {
+    http_client(
+        &config.proxy,
+        &config.user_agent,
+        config.connect_timeout,
+        config.request_timeout,
+    )
+}
+
+/// [`build_http_client`] from its parts, for commands that run without a full
+/// [`Config`].
+pub(crate) fn http_client(
+    proxy: &ProxySettings,
+    user_agent: &str,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+) -> Result<reqwest::Client> {
+    let user_agent = HeaderValue::from_str(user_agent)
+        .map_err(|_| anyhow!("Invalid USER_AGENT {:?}", user_agent))?;
+    let mut builder = reqwest::Client::builder()
+        .no_proxy()
+        .user_agent(user_agent)
+        .connect_timeout(connect_timeout)
+        .timeout(request_timeout)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT);
+
+    let no_proxy = proxy.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string);
+    type MakeProxy = fn(String) -> reqwest::Result<reqwest::Proxy>;
+    let proxies: [(&Option<String>, MakeProxy); 3] = [
+        (&proxy.http, reqwest::Proxy::http),
+        (&proxy.https, reqwest::Proxy::https),
+        (&proxy.all, reqwest::Proxy::all),
+    ];
+    for (url, make) in proxies {
+        if let Some(url) = url {
+            let proxy = make(url.clone())
+                .map_err(|e| anyhow!("Invalid proxy URL {:?}: {}", redact(url), e))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+        }
+    }
+    builder.build().context("Building HTTP client")
+}
+
+/// `url` with any password replaced, for error messages.
+fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            parsed.set_password(Some("***")).ok();
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// A failed upload with `body`, parsed like [`check_response`] does.
+    fn with_body(status: u16, body: &str) -> HttpStatusError {
+        HttpStatusError {
+            what: "Upload".to_string(),
+            status: StatusCode::from_u16(status).unwrap(),
+            body: body.to_string(),
+            api_error: serde_json::from_str(body).ok(),
+            retry_after: None,
+        }
+    }
+
+    fn status_error(status: u16, retry_after: Option<u64>) -> anyhow::Error {
+        let retry_after = retry_after.map(Duration::from_secs);
+        HttpStatusError { retry_after, ..with_body(status, "") }.into()
+    }
+
+    #[test]
+    fn error_bodies_show_their_tag_and_summary() {
+        let conflict = with_body(
+            409,
+            r#"{"error_summary": "path/conflict/file/..", "error": {".tag": "path", "reason": {".tag": "conflict"}}}"#,
+        );
+        assert_eq!(
+            conflict.to_string(),
+            "Upload failed: HTTP 409 Conflict (path): path/conflict/file/.."
+        );
+        assert!(conflict.is_path_conflict());
+
+        let plain = with_body(500, "oops");
+        assert_eq!(
+            plain.to_string(),
+            "Upload failed: HTTP 500 Internal Server Error - oops"
+        );
+        assert!(!plain.is_path_conflict());
+    }
+
+    #[test]
+    fn failures_are_classified_by_status() {
+        let classify =
+            |status, retry_after| UploadError::from(status_error(status, retry_after));
+        assert!(matches!(classify(401, None), UploadError::Unauthorized(_)));
+        assert!(matches!(
+            classify(429, Some(7)),
+            UploadError::RateLimited { retry_after: Some(d), .. } if d.as_secs() == 7
+        ));
+        assert!(matches!(classify(503, None), UploadError::Transient(_)));
+        assert!(matches!(classify(409, None), UploadError::Permanent(_)));
+
+        let wrapped = status_error(500, None).context("Upload a.txt");
+        assert!(UploadError::from(wrapped).is_retryable());
+        let local = anyhow!(std::io::Error::other("disk on fire"));
+        assert!(matches!(UploadError::from(local), UploadError::Permanent(_)));
+        let classified =
+            anyhow::Error::from(UploadError::Integrity(anyhow!("mismatch")));
+        assert!(matches!(UploadError::from(classified), UploadError::Integrity(_)));
+    }
+
+    #[tokio::test]
+    async fn only_retryable_failures_are_retried() {
+        let policy =
+            RetryPolicy { max_retries: 3, base_delay: Duration::ZERO, jitter: false };
+        let attempts = Cell::new(0);
+        let result = with_retries(&policy, "Upload", || {
+            attempts.set(attempts.get() + 1);
+            let status = if attempts.get() < 3 { 503 } else { 200 };
+            async move {
+                if status == 200 { Ok(()) } else { Err(status_error(status, None)) }
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let result: Result<(), _> = with_retries(&policy, "Upload", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(status_error(400, None)) }
+        })
+        .await;
+        assert!(matches!(result, Err(UploadError::Permanent(_))));
+        assert_eq!(attempts.get(), 1);
+
+        attempts.set(0);
+        let result: Result<(), _> = with_retries(&policy, "Upload", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(status_error(500, None)) }
+        })
+        .await;
+        assert!(matches!(result, Err(UploadError::Transient(_))));
+        assert_eq!(attempts.get(), 4);
+    }
+}
@@ -0,0 +1,411 @@
+//! The upload log recording which files have already been sent to Dropbox.
+
+use anyhow::{Context, Result};
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::{Config, LedgerKind};
+use crate::files::{sanitized_path, utf8_path};
+
+mod sqlite;
+
+use sqlite::SqliteLog;
+
+pub(crate) fn ensure_log_exists(path: &Path) -> Result<()> {
+    if !path.exists() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(path)?;
+    }
+    Ok(())
+}
+
+/// One uploaded file, as passed to [`UploadLog::record`].
+#[derive(Debug, Clone, Copy)]
+pub struct LogEntry<'a> {
+    pub path: &'a Path,
+    pub content_hash: &'a str,
+    pub size: u64,
+    /// Dropbox revision of the uploaded file, if it reported one.
+    pub rev: Option<&'a str>,
+    /// Where it was uploaded to, including any compression suffix.
+    pub remote_path: &'a str,
+    /// Its quick hash, with `quick_hash` set.
+    pub quick_hash: Option<&'a str>,
+}
+
+/// An entry read back from the upload log.
+#[derive(Debug, Clone)]
+pub struct LoggedUpload {
+    /// The local path the file was uploaded from, relative to
+    /// `current_directory` if it was logged with `log_paths` set to `relative`.
+    pub path: PathBuf,
+    /// Missing for entries of versions that logged only the path.
+    pub content_hash: Option<String>,
+    /// Missing for entries of versions that did not record it.
+    pub remote_path: Option<String>,
+}
+
+/// The record of uploaded files, kept in `uploaded_files_log` or, with
+/// `LEDGER=sqlite`, in a SQLite database.
+#[derive(Debug)]
+pub enum UploadLog {
+    File(FileLog),
+    Sqlite(SqliteLog),
+}
+
+impl UploadLog {
+    /// Opens the ledger `config.ledger` selects. In a dry run nothing is
+    /// created on disk.
+    pub fn open(config: &Config) -> Result<Self> {
+        Ok(match config.ledger {
+            LedgerKind::File => Self::File(FileLog::open(&config.uploaded_files_log)?),
+            LedgerKind::Sqlite => Self::Sqlite(SqliteLog::open(
+                &config.ledger_db,
+                &config.uploaded_files_log,
+                config.dry_run,
+            )?),
+        })
+    }
+
+    /// Whether a file with content hash `hash`, or a path-only entry for
+    /// `file_path`, has been logged.
+    pub fn contains(&self, file_path: &Path, hash: &str) -> Result<bool> {
+        match self {
+            Self::File(log) => log.contains(file_path, hash),
+            Self::Sqlite(log) => log.contains(file_path, hash),
+        }
+    }
+
+    /// The content hash last logged with quick hash `quick_hash`, if any.
+    pub fn quick_hash_match(&self, quick_hash: &str) -> Result<Option<String>> {
+        match self {
+            Self::File(log) => log.quick_hash_match(quick_hash),
+            Self::Sqlite(log) => log.quick_hash_match(quick_hash),
+        }
+    }
+
+    pub fn record(&self, entry: &LogEntry) -> Result<()> {
+        match self {
+            Self::File(log) => log.record(entry),
+            Self::Sqlite(log) => log.record(entry),
+        }
+    }
+
+    /// Every logged upload, oldest first.
+    pub fn entries(&self) -> Result<Vec<LoggedUpload>> {
+        match self {
+            Self::File(log) => log.entries(),
+            Self::Sqlite(log) => log.entries(),
+        }
+    }
+}
+
+/// The plain-text upload log, read once and then kept in memory alongside the
+/// file.
+///
+/// Log lines are `<content_hash>\t<path>\t<remote_path>`, plus `\t<quick_hash>`
+/// if one was computed; the remote path is missing from lines written before it
+/// was recorded. Earlier versions added a quick hash to a logged upload by
+/// repeating its line with the quick hash on the end. Lines without a tab were
+/// written by older versions that keyed on the path alone; those still match by
+/// path, either as the file's path is now or as it is after space sanitization,
+/// which is the name that was logged.
+#[derive(Debug)]
+pub struct FileLog {
+    path: PathBuf,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// Content hashes, with the remote path logged for each.
+    hashes: HashMap<String, Option<String>>,
+    /// Content hash by quick hash.
+    quick_hashes: HashMap<String, String>,
+    legacy_paths: HashSet<String>,
+}
+
+/// A parsed line of the plain-text log.
+enum LogLine {
+    Hashed {
+        content_hash: String,
+        path: String,
+        remote_path: Option<String>,
+        quick_hash: Option<String>,
+    },
+    Legacy {
+        path: String,
+    },
+}
+
+/// Reads the plain-text log at `path`; a missing file has no lines.
+///
+/// Every entry is written with its newline in one go, so a last line without
+/// one was cut short by a crash and is ignored.
+fn read_log_lines(path: &Path) -> Result<Vec<LogLine>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Open upload log {:?}", path)),
+    };
+    let mut reader = BufReader::new(f);
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader
+            .read_line(&mut line)
+            .with_context(|| format!("Read upload log {:?}", path))?
+            == 0
+        {
+            return Ok(lines);
+        }
+        let Some(complete) = line.strip_suffix('\n') else {
+            warn!("Ignoring incomplete last line of upload log {:?}: {:?}", path, line);
+            return Ok(lines);
+        };
+        let complete = complete.strip_suffix('\r').unwrap_or(complete);
+        let mut fields = complete.split('\t');
+        let first = fields.next().unwrap_or_default().to_string();
+        lines.push(match fields.next() {
+            Some(logged) => LogLine::Hashed {
+                content_hash: first,
+                path: logged.to_string(),
+                remote_path: fields.next().map(str::to_string),
+                quick_hash: fields.next().map(str::to_string),
+            },
+            None => LogLine::Legacy { path: first },
+        });
+    }
+}
+
+impl FileLog {
+    /// Reads the log at `path`. A missing file is an empty log; it is created
+    /// on the first [`FileLog::record`].
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut entries = Entries::default();
+        for line in read_log_lines(path)? {
+            match line {
+                LogLine::Hashed { content_hash, remote_path, quick_hash, .. } => {
+                    if let Some(quick_hash) = quick_hash {
+                        entries.quick_hashes.insert(quick_hash, content_hash.clone());
+                    }
+                    entries.hashes.insert(content_hash, remote_path);
+                }
+                LogLine::Legacy { path } => {
+                    entries.legacy_paths.insert(path);
+                }
+            }
+        }
+        Ok(Self { path: path.to_path_buf(), entries: Mutex::new(entries) })
+    }
+
+    fn entries(&self) -> Result<Vec<LoggedUpload>> {
+        let _entries = self.entries.lock().expect("upload log lock poisoned");
+        let mut seen = HashSet::new();
+        Ok(read_log_lines(&self.path)?
+            .into_iter()
+            .filter_map(|line| match line {
+                LogLine::Hashed { content_hash, path, remote_path, .. } => {
+                    // Lines repeated to add a quick hash are the same upload.
+                    seen.insert((content_hash.clone(), remote_path.clone())).then(|| {
+                        LoggedUpload {
+                            path: PathBuf::from(path),
+                            content_hash: Some(content_hash),
+                            remote_path,
+                        }
+                    })
+                }
+                LogLine::Legacy { path } => Some(LoggedUpload {
+                    path: PathBuf::from(path),
+                    content_hash: None,
+                    remote_path: None,
+                }),
+            })
+            .collect())
+    }
+
+    fn contains(&self, file_path: &Path, hash: &str) -> Result<bool> {
+        let entries = self.entries.lock().expect("upload log lock poisoned");
+        if entries.hashes.contains_key(hash) {
+            return Ok(true);
+        }
+        if entries.legacy_paths.is_empty() {
+            return Ok(false);
+        }
+        let sanitized = sanitized_path(file_path)?;
+        let logged = |path: &Path| {
+            path.to_str().is_some_and(|path| entries.legacy_paths.contains(path))
+        };
+        Ok(logged(file_path) || logged(&sanitized))
+    }
+
+    fn quick_hash_match(&self, quick_hash: &str) -> Result<Option<String>> {
+        let entries = self.entries.lock().expect("upload log lock poisoned");
+        Ok(entries.quick_hashes.get(quick_hash).cloned())
+    }
+
+    /// Appends the entry to the log file and the in-memory set.
+    ///
+    /// Appends from this process are serialized by `entries`, and those from
+    /// other processes sharing the log by an exclusive lock on the file. Each
+    /// line goes out in a single write and is synced before the lock is
+    /// released. If a previous writer crashed mid-line, the new line starts on
+    /// a fresh one rather than being glued to the fragment.
+    fn record(&self, entry: &LogEntry) -> Result<()> {
+        let mut entries = self.entries.lock().expect("upload log lock poisoned");
+        ensure_log_exists(&self.path)?;
+        let mut f = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Open upload log {:?}", self.path))?;
+        f.lock().with_context(|| format!("Lock upload log {:?}", self.path))?;
+
+        let mut line = String::new();
+        if f.metadata()?.len() > 0 {
+            let mut last = [0u8];
+            f.seek(SeekFrom::End(-1))?;
+            f.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                line.push('\n');
+            }
+        }
+        line.push_str(&format!(
+            "{}\t{}\t{}",
+            entry.content_hash,
+            utf8_path(entry.path)?,
+            entry.remote_path
+        ));
+        if let Some(quick_hash) = entry.quick_hash {
+            line.push_str(&format!("\t{}", quick_hash));
+        }
+        line.push('\n');
+        f.write_all(line.as_bytes())
+            .and_then(|()| f.sync_data())
+            .with_context(|| format!("Append to upload log {:?}", self.path))?;
+        entries
+            .hashes
+            .insert(entry.content_hash.to_string(), Some(entry.remote_path.to_string()));
+        if let Some(quick_hash) = entry.quick_hash {
+            entries
+                .quick_hashes
+                .insert(quick_hash.to_string(), entry.content_hash.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn entry<'a>(path: &'a Path, content_hash: &'a str) -> LogEntry<'a> {
+        LogEntry {
+            path,
+            content_hash,
+            size: 3,
+            rev: Some("rev1"),
+            remote_path: "/a.txt",
+            quick_hash: None,
+        }
+    }
+
+    /// A plain-text log with one hashed line and one path-only line as older
+    /// versions wrote it, after renaming `/src/old file.txt`.
+    fn legacy_log(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("uploaded.log");
+        fs::write(&path, "abc\t/src/a.txt\n/src/old_file.txt\n").unwrap();
+        path
+    }
+
+    #[test]
+    fn file_log_survives_a_reopen() {
+        let dir = TempDir::new();
+        let path = dir.path().join("logs/uploaded.log");
+        let log = FileLog::open(&path).unwrap();
+        assert!(!log.contains(Path::new("/src/a.txt"), "abc").unwrap());
+
+        log.record(&entry(Path::new("/src/a.txt"), "abc")).unwrap();
+        assert!(log.contains(Path::new("/src/a.txt"), "abc").unwrap());
+
+        let log = FileLog::open(&path).unwrap();
+        assert!(log.contains(Path::new("/src/moved.txt"), "abc").unwrap());
+        assert!(!log.contains(Path::new("/src/a.txt"), "def").unwrap());
+    }
+
+    #[test]
+    fn quick_hashes_are_kept_by_both_logs() {
+        let dir = TempDir::new();
+        let text_log = dir.path().join("uploaded.log");
+        let file_log = UploadLog::File(FileLog::open(&text_log).unwrap());
+        let db = dir.path().join("ledger.sqlite3");
+        let sqlite_log =
+            UploadLog::Sqlite(SqliteLog::open(&db, &text_log, false).unwrap());
+        for log in [&file_log, &sqlite_log] {
+            let mut with_quick = entry(Path::new("/src/a.txt"), "abc");
+            with_quick.quick_hash = Some("quick-a");
+            log.record(&with_quick).unwrap();
+            log.record(&entry(Path::new("/src/b.txt"), "def")).unwrap();
+            assert_eq!(log.quick_hash_match("quick-a").unwrap().as_deref(), Some("abc"));
+            assert_eq!(log.quick_hash_match("quick-b").unwrap(), None);
+
+            let mut changed = entry(Path::new("/src/b.txt"), "def");
+            changed.quick_hash = Some("quick-b");
+            log.record(&changed).unwrap();
+            assert_eq!(log.quick_hash_match("quick-b").unwrap().as_deref(), Some("def"));
+        }
+        drop(sqlite_log);
+
+        let reopened = FileLog::open(&text_log).unwrap();
+        assert_eq!(
+            reopened.quick_hash_match("quick-a").unwrap().as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            reopened.quick_hash_match("quick-b").unwrap().as_deref(),
+            Some("def")
+        );
+        let reopened = SqliteLog::open(&db, &text_log, true).unwrap();
+        assert_eq!(
+            reopened.quick_hash_match("quick-b").unwrap().as_deref(),
+            Some("def")
+        );
+    }
+
+    #[test]
+    fn file_log_matches_legacy_lines_by_path() {
+        let dir = TempDir::new();
+        let log = FileLog::open(&legacy_log(&dir)).unwrap();
+
+        assert!(log.contains(Path::new("/src/old file.txt"), "new").unwrap());
+        assert!(log.contains(Path::new("/src/old_file.txt"), "new").unwrap());
+        assert!(!log.contains(Path::new("/src/other.txt"), "new").unwrap());
+    }
+
+    #[test]
+    fn sqlite_log_imports_the_text_log_and_survives_a_reopen() {
+        let dir = TempDir::new();
+        let text_log = legacy_log(&dir);
+        let db = dir.path().join("ledger.sqlite3");
+        let log = SqliteLog::open(&db, &text_log, false).unwrap();
+        assert!(log.contains(Path::new("/src/moved.txt"), "abc").unwrap());
+        assert!(log.contains(Path::new("/src/old file.txt"), "new").unwrap());
+        assert!(!log.contains(Path::new("/src/other.txt"), "new").unwrap());
+
+        log.record(&entry(Path::new("/src/b.txt"), "def")).unwrap();
+        drop(log);
+        fs::write(&text_log, "").unwrap();
+
+        let log = SqliteLog::open(&db, &text_log, true).unwrap();
+        assert!(log.contains(Path::new("/src/b.txt"), "def").unwrap());
+        assert!(log.contains(Path::new("/src/a.txt"), "abc").unwrap());
+    }
+}
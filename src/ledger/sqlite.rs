@@ -0,0 +1,195 @@
+//! The SQLite upload ledger.
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use log::info;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{LogEntry, LogLine, LoggedUpload, read_log_lines};
+use crate::files::{sanitized_path, utf8_path};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS uploads (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL,
+        content_hash TEXT,
+        size INTEGER,
+        uploaded_at TEXT,
+        rev TEXT,
+        remote_path TEXT,
+        quick_hash TEXT
+    );
+    CREATE INDEX IF NOT EXISTS uploads_content_hash ON uploads (content_hash);
+    CREATE INDEX IF NOT EXISTS uploads_path ON uploads (path);
+";
+
+/// One `uploads` row per uploaded file. Rows imported from path-only lines of
+/// the plain-text log have no `content_hash` and match by path, like they do
+/// there.
+#[derive(Debug)]
+pub struct SqliteLog {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteLog {
+    /// Opens or creates the database at `db_path`. A new database is seeded with
+    /// the entries of the plain-text log at `text_log`, so switching ledgers
+    /// does not re-upload everything.
+    ///
+    /// With `read_only` an existing database is opened read-only and a missing
+    /// one is built in memory instead of on disk.
+    pub(super) fn open(
+        db_path: &Path,
+        text_log: &Path,
+        read_only: bool,
+    ) -> Result<Self> {
+        let is_new = !db_path.exists();
+        let open = || -> rusqlite::Result<Connection> {
+            match (read_only, is_new) {
+                (true, true) => Connection::open_in_memory(),
+                (true, false) => Connection::open_with_flags(
+                    db_path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY,
+                ),
+                (false, _) => Connection::open(db_path),
+            }
+        };
+        if !read_only && let Some(parent) = db_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut conn = open().with_context(|| format!("Open ledger {:?}", db_path))?;
+        if is_new || !read_only {
+            conn.execute_batch(SCHEMA)
+                .with_context(|| format!("Create ledger schema in {:?}", db_path))?;
+            migrate(&conn).with_context(|| format!("Migrate ledger {:?}", db_path))?;
+        }
+        if is_new {
+            import_text_log(&mut conn, text_log)?;
+        }
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    pub(super) fn contains(&self, file_path: &Path, hash: &str) -> Result<bool> {
+        let conn = self.conn.lock().expect("ledger lock poisoned");
+        // A path that is not UTF-8 cannot have been logged, so only its hash
+        // can match.
+        let original = file_path.to_str();
+        let sanitized = sanitized_path(file_path)?;
+        let sanitized = sanitized.to_str();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM uploads
+                 WHERE content_hash = ?1
+                    OR (content_hash IS NULL AND path IN (?2, ?3))
+                 LIMIT 1",
+                params![hash, original, sanitized],
+                |_| Ok(()),
+            )
+            .optional()
+            .context("Query ledger")?;
+        Ok(found.is_some())
+    }
+
+    pub(super) fn quick_hash_match(&self, quick_hash: &str) -> Result<Option<String>> {
+        let conn = self.conn.lock().expect("ledger lock poisoned");
+        let found = conn
+            .query_row(
+                "SELECT content_hash FROM uploads WHERE quick_hash = ?1
+                 ORDER BY rowid DESC LIMIT 1",
+                params![quick_hash],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .context("Query ledger")?;
+        Ok(found.flatten())
+    }
+
+    pub(super) fn entries(&self) -> Result<Vec<LoggedUpload>> {
+        let conn = self.conn.lock().expect("ledger lock poisoned");
+        let mut query = conn.prepare(
+            "SELECT path, content_hash, remote_path FROM uploads ORDER BY id",
+        )?;
+        let rows = query.query_map([], |row| {
+            Ok(LoggedUpload {
+                path: PathBuf::from(row.get::<_, String>(0)?),
+                content_hash: row.get(1)?,
+                remote_path: row.get(2)?,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>().context("Read ledger")
+    }
+
+    pub(super) fn record(&self, entry: &LogEntry) -> Result<()> {
+        let conn = self.conn.lock().expect("ledger lock poisoned");
+        conn.execute(
+            "INSERT INTO uploads
+                 (path, content_hash, size, uploaded_at, rev, remote_path, quick_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                utf8_path(entry.path)?,
+                entry.content_hash,
+                entry.size as i64,
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                entry.rev,
+                entry.remote_path,
+                entry.quick_hash,
+            ],
+        )
+        .context("Insert into ledger")?;
+        Ok(())
+    }
+}
+
+/// Adds the columns of later versions to a database created without them.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let has_remote_path =
+        conn.prepare("SELECT remote_path FROM uploads LIMIT 0").is_ok();
+    if !has_remote_path {
+        conn.execute_batch("ALTER TABLE uploads ADD COLUMN remote_path TEXT")?;
+    }
+    let has_quick_hash = conn.prepare("SELECT quick_hash FROM uploads LIMIT 0").is_ok();
+    if !has_quick_hash {
+        conn.execute_batch("ALTER TABLE uploads ADD COLUMN quick_hash TEXT")?;
+    }
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS uploads_quick_hash ON uploads (quick_hash)",
+    )
+}
+
+fn import_text_log(conn: &mut Connection, text_log: &Path) -> Result<()> {
+    let lines = read_log_lines(text_log)?;
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO uploads (path, content_hash, remote_path, quick_hash)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for line in &lines {
+            match line {
+                LogLine::Hashed { content_hash, path, remote_path, quick_hash } => {
+                    insert.execute(params![
+                        path,
+                        content_hash,
+                        remote_path,
+                        quick_hash
+                    ])?
+                }
+                LogLine::Legacy { path } => insert.execute(params![
+                    path,
+                    None::<&str>,
+                    None::<&str>,
+                    None::<&str>
+                ])?,
+            };
+        }
+    }
+    tx.commit().context("Import upload log into ledger")?;
+    info!("Imported {} entries from {:?} into the ledger", lines.len(), text_log);
+    Ok(())
+}
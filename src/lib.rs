@@ -0,0 +1,434 @@
+//! Backs up local files to Dropbox: scans a directory for matching files,
+//! uploads each one, records it in an upload log and moves it out of the way.
+//! Uploads go through a [`Backend`]: Dropbox, or a local directory for testing.
+//!
+//! The `fs_library` binary is a thin command line around [`run`] and [`watch`];
+//! programs embedding the uploader can also drive [`collect_files`] and
+//! [`upload_file`] themselves, or hand single files to [`upload_path`].
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future;
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use http::{http_status_error, is_insufficient_space};
+use progress::Bar;
+
+pub mod auth;
+pub mod backend;
+pub mod config;
+pub mod dropbox;
+mod encrypt;
+mod files;
+mod hook;
+pub mod http;
+pub mod ledger;
+pub mod list;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+mod notify;
+mod prepare;
+mod progress;
+pub mod save_url;
+pub mod scan;
+#[cfg(test)]
+mod test_util;
+pub mod throttle;
+pub mod upload;
+pub mod verify;
+pub mod watch;
+
+pub use auth::{ShortToken, TokenCache, refresh_token};
+pub use backend::{AnyBackend, Backend, DropboxBackend, LocalBackend, Payload};
+pub use config::{
+    BackendKind, CollisionPolicy, Compression, Config, ConfigBuilder, EncryptionKey,
+    LedgerKind, LogPaths, OnConflict, OrderBy, PostUploadAction, RetryPolicy, Settings,
+    TokenStore, UploadMode,
+};
+pub use dropbox::{Account, DropboxClient, FileMetadata};
+pub use encrypt::decrypt_file;
+pub use http::{DropboxApiError, UploadError, build_http_client};
+pub use ledger::{LoggedUpload, UploadLog};
+pub use list::{ListedFile, list};
+pub use lock::InstanceLock;
+pub use save_url::{SaveUrlSummary, save_urls};
+pub use scan::{Scan, SkipReason, collect_files, scan, scan_async};
+pub use throttle::RateLimiter;
+pub use upload::{UploadOutcome, upload_file};
+pub use verify::{VerifySummary, verify};
+pub use watch::{poll, watch};
+
+/// Per-file results and statistics of a [`run`].
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    /// Files with a wanted extension the scan found, skipped ones included.
+    pub scanned: usize,
+    pub uploaded: Vec<UploadedFile>,
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+    /// Files never attempted because `fail_fast` or `max_bytes_per_run` stopped
+    /// the run early.
+    pub not_attempted: usize,
+    /// Whether `max_bytes_per_run` was reached.
+    pub byte_limit_reached: bool,
+    /// Whether Dropbox refused an upload for lack of space, stopping the run.
+    pub out_of_space: bool,
+    /// Total size of the uploaded files, before compression or encryption.
+    pub bytes_uploaded: u64,
+    pub started_at: DateTime<Utc>,
+    pub duration: Duration,
+}
+
+/// A file uploaded by a [`run`], as `MANIFEST_FILE` lists it.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadedFile {
+    /// Where the scan found it.
+    pub local_path: PathBuf,
+    pub dropbox_path: String,
+    /// Before compression or encryption.
+    pub size: u64,
+    pub content_hash: String,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+/// The figures of a [`RunSummary`]: a line of `STATS_FILE` and part of the
+/// completion webhook's payload.
+#[derive(Serialize)]
+pub(crate) struct RunStats {
+    started_at: DateTime<Utc>,
+    duration_secs: f64,
+    scanned: usize,
+    uploaded: usize,
+    skipped: usize,
+    skipped_by_reason: BTreeMap<&'static str, usize>,
+    failed: usize,
+    not_attempted: usize,
+    out_of_space: bool,
+    bytes_uploaded: u64,
+    bytes_per_sec: f64,
+}
+
+impl RunSummary {
+    /// Counts the result of processing `file`, logging it if it failed. Returns
+    /// `false` once `fail_fast` or `max_bytes_per_run` says no further uploads
+    /// should be started, Dropbox has run out of space for them or the upload
+    /// log has failed.
+    fn record(
+        &mut self,
+        config: &Config,
+        file: PathBuf,
+        result: Result<UploadOutcome>,
+    ) -> bool {
+        let (outcome, upload_log_failed) = match result {
+            Ok(outcome) => (outcome, false),
+            Err(e) => (UploadOutcome::Failed(e), true),
+        };
+        match &outcome {
+            UploadOutcome::Uploaded { bytes, .. } => metrics::uploaded(*bytes),
+            UploadOutcome::Skipped(_) => {}
+            UploadOutcome::Failed(e) => {
+                match http_status_error(e).and_then(|e| e.api_error.as_ref()?.tag()) {
+                    Some(tag) => error!(
+                        path:% = file.display(),
+                        error_tag = tag;
+                        "Failed to process {:?}: {}", file, e
+                    ),
+                    None => {
+                        error!(path:% = file.display(); "Failed to process {:?}: {}", file, e)
+                    }
+                }
+                metrics::failed();
+            }
+        }
+        let failed = matches!(outcome, UploadOutcome::Failed(_));
+        let go_on = !(upload_log_failed || failed && config.fail_fast);
+        match outcome {
+            UploadOutcome::Uploaded { bytes, remote_path, content_hash } => {
+                self.bytes_uploaded += bytes;
+                self.uploaded.push(UploadedFile {
+                    local_path: file,
+                    dropbox_path: remote_path,
+                    size: bytes,
+                    content_hash,
+                    uploaded_at: Utc::now(),
+                });
+            }
+            UploadOutcome::Skipped(reason) => self.skipped.push((file, reason)),
+            UploadOutcome::Failed(e) => {
+                if is_insufficient_space(&e) {
+                    self.out_of_space = true;
+                }
+                self.failed.push((file, e));
+            }
+        }
+        if self.bytes_uploaded >= config.max_bytes_per_run {
+            self.byte_limit_reached = true;
+        }
+        go_on && !self.byte_limit_reached && !self.out_of_space
+    }
+
+    /// Number of skipped files by reason.
+    pub fn skipped_by_reason(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for (_, reason) in &self.skipped {
+            *counts.entry(reason.as_str()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Average upload rate over the whole run, scan included.
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs > 0.0 { self.bytes_uploaded as f64 / secs } else { 0.0 }
+    }
+
+    fn log(&self) {
+        let reasons = self
+            .skipped_by_reason()
+            .iter()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect::<Vec<_>>();
+        let reasons = if reasons.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", reasons.join(", "))
+        };
+        info!(
+            scanned = self.scanned,
+            uploaded = self.uploaded.len(),
+            skipped = self.skipped.len(),
+            failed = self.failed.len(),
+            bytes = self.bytes_uploaded,
+            duration_ms = self.duration.as_millis() as u64;
+            "Summary: {} scanned, {} uploaded, {} skipped{}, {} failed; {} bytes in {:.1?} ({:.0} bytes/s)",
+            self.scanned,
+            self.uploaded.len(),
+            self.skipped.len(),
+            reasons,
+            self.failed.len(),
+            self.bytes_uploaded,
+            self.duration,
+            self.bytes_per_sec()
+        );
+        for (file, e) in &self.failed {
+            error!("  {:?}: {}", file, e);
+        }
+        if self.out_of_space {
+            error!(
+                "Dropbox is out of space; stopped with {} file(s) not attempted. Free up \
+                 space or upgrade the plan, then run again",
+                self.not_attempted
+            );
+        } else if self.not_attempted > 0 && self.byte_limit_reached {
+            info!(
+                "Reached MAX_BYTES_PER_RUN after {} bytes, {} file(s) left for the next run",
+                self.bytes_uploaded, self.not_attempted
+            );
+        } else if self.not_attempted > 0 {
+            warn!(
+                "Stopped after the first failure, {} file(s) not attempted",
+                self.not_attempted
+            );
+        }
+    }
+
+    /// Appends the statistics to `path` as one JSON line.
+    fn write_stats(&self, path: &Path) -> Result<()> {
+        let mut line = serde_json::to_vec(&self.stats())?;
+        line.push(b'\n');
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Replaces `path` with a JSON array of the uploaded files, through a
+    /// temporary file next to it, so a reader never sees half a manifest.
+    fn write_manifest(&self, path: &Path) -> Result<()> {
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp = path.with_file_name(temp_name);
+        let json = serde_json::to_vec_pretty(&self.uploaded)?;
+        let written = fs::write(&temp, json).and_then(|()| fs::rename(&temp, path));
+        if written.is_err() {
+            fs::remove_file(&temp).ok();
+        }
+        Ok(written?)
+    }
+
+    pub(crate) fn stats(&self) -> RunStats {
+        RunStats {
+            started_at: self.started_at,
+            duration_secs: self.duration.as_secs_f64(),
+            scanned: self.scanned,
+            uploaded: self.uploaded.len(),
+            skipped: self.skipped.len(),
+            skipped_by_reason: self.skipped_by_reason(),
+            failed: self.failed.len(),
+            not_attempted: self.not_attempted,
+            out_of_space: self.out_of_space,
+            bytes_uploaded: self.bytes_uploaded,
+            bytes_per_sec: self.bytes_per_sec(),
+        }
+    }
+}
+
+/// Runs one backup pass over `config.current_directory`. A failed file does not
+/// stop the others unless `fail_fast` is set, in which case no new uploads are
+/// started after it; the ones already in flight still finish.
+pub async fn run(config: &Config) -> Result<RunSummary> {
+    let backend = AnyBackend::connect(config).await?;
+    let upload_log = UploadLog::open(config)?;
+    run_with(&backend, config, &upload_log).await
+}
+
+/// Uploads only `local_file`, which need not be in `current_directory` or match
+/// the scan's filters, the way [`run`] would upload one of the files it found:
+/// skipped if the upload log has it, otherwise uploaded, logged and moved away
+/// per `post_upload_action`. A dry run only logs what would happen and reports
+/// the file as skipped with [`SkipReason::DryRun`].
+///
+/// This sets up the backend and upload log on every call; to upload many files
+/// on a schedule of your own, set those up once and call [`upload_file`]. A file
+/// that could not be uploaded is [`UploadOutcome::Failed`]; an `Err` means the
+/// backend or upload log could not be set up or used. To tell a failure worth
+/// retrying from one that is not, convert the error to an [`UploadError`].
+pub async fn upload_path(config: &Config, local_file: &Path) -> Result<UploadOutcome> {
+    // Relative to `current_directory`, which is absolute, for `preserve_tree`.
+    let local_file = &std::path::absolute(local_file)?;
+    let backend = AnyBackend::connect(config).await?;
+    let upload_log = UploadLog::open(config)?;
+    if config.dry_run {
+        return Ok(match upload::dry_run_file(config, &upload_log, local_file).await {
+            Ok(()) => UploadOutcome::Skipped(SkipReason::DryRun),
+            Err(e) => UploadOutcome::Failed(e),
+        });
+    }
+    upload_file(&backend, config, &upload_log, local_file).await
+}
+
+/// [`run`] with the backend and upload log already set up, notifying the
+/// configured webhooks of the outcome.
+pub(crate) async fn run_with(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+) -> Result<RunSummary> {
+    let started = Instant::now();
+    let result = run_pass(backend, config, upload_log).await;
+    if !config.dry_run {
+        notify::run_finished(config, &result, started.elapsed()).await;
+    }
+    result
+}
+
+async fn run_pass(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+) -> Result<RunSummary> {
+    if !config.dry_run {
+        if config.post_upload_action == PostUploadAction::Move {
+            fs::create_dir_all(&config.uploaded_directory).ok();
+        }
+        if config.ledger == LedgerKind::File {
+            ledger::ensure_log_exists(&config.uploaded_files_log).ok();
+        }
+    }
+
+    let started_at = Utc::now();
+    let started = Instant::now();
+    let Scan { files, skipped } = scan_async(config).await?;
+    let matched = files.len();
+    let mut summary = if files.is_empty() {
+        info!("No files matched the provided extensions.");
+        RunSummary::default()
+    } else {
+        process_files(backend, config, upload_log, files).await
+    };
+    summary.scanned = matched + skipped.len();
+    summary.skipped.extend(skipped);
+    summary.started_at = started_at;
+    summary.duration = started.elapsed();
+
+    if config.dry_run {
+        if matched > 0 {
+            info!("Dry run complete, {} file(s) matched.", matched);
+        }
+        return Ok(summary);
+    }
+    if matched > 0 {
+        summary.log();
+    }
+    if let Some(path) = &config.stats_file
+        && let Err(e) = summary.write_stats(path)
+    {
+        warn!("Could not write run statistics to {:?}: {}", path, e);
+    }
+    if let Some(path) = &config.manifest_file
+        && let Err(e) = summary.write_manifest(path)
+    {
+        warn!("Could not write upload manifest to {:?}: {}", path, e);
+    }
+    if matched > 0 {
+        info!("Done.");
+    }
+    Ok(summary)
+}
+
+/// Uploads `files` (or, in a dry run, reports on them) up to
+/// `max_concurrent_uploads` at a time.
+pub(crate) async fn process_files(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+    files: Vec<PathBuf>,
+) -> RunSummary {
+    let mut summary = RunSummary::default();
+
+    if config.dry_run {
+        for file in files {
+            if let Err(e) = upload::dry_run_file(config, upload_log, &file).await {
+                error!(path:% = file.display(); "[dry-run] Failed to inspect {:?}: {}", file, e);
+                summary.failed.push((file, e));
+            }
+        }
+        return summary;
+    }
+
+    // Uploads are multiplexed on this task rather than spawned, so the
+    // synchronous log append in `upload_file` never interleaves with another.
+    let total = files.len();
+    let bar = Bar::files(total as u64);
+    metrics::set_queue_depth(total);
+    let stop = Cell::new(false);
+    let mut results = stream::iter(files)
+        .take_while(|_| future::ready(!stop.get()))
+        .map(|file| async move {
+            let result = upload_file(backend, config, upload_log, &file).await;
+            (file, result)
+        })
+        .buffer_unordered(config.max_concurrent_uploads);
+    let mut done = 0;
+    while let Some((file, result)) = results.next().await {
+        if !summary.record(config, file, result) {
+            stop.set(true);
+        }
+        bar.inc(1);
+        done += 1;
+        metrics::set_queue_depth(total - done);
+    }
+    // Files left out by `fail_fast` or the byte limit are no longer queued either.
+    metrics::set_queue_depth(0);
+
+    summary.not_attempted =
+        total - summary.uploaded.len() - summary.skipped.len() - summary.failed.len();
+    summary
+}
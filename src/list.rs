@@ -0,0 +1,66 @@
+//! What a backup pass would do with each file, without doing any of it.
+
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::files::content_hash_async;
+use crate::ledger::UploadLog;
+use crate::scan::{Scan, SkipReason, scan_async};
+use crate::upload::dropbox_path;
+
+/// A file the scan found, as [`list`] reports it.
+#[derive(Debug)]
+pub struct ListedFile {
+    pub path: PathBuf,
+    /// Missing if its metadata could not be read.
+    pub size: Option<u64>,
+    /// Why a pass would not upload it, or `None` if it would.
+    pub skip: Option<SkipReason>,
+    /// Where it would be uploaded to, or why it cannot be.
+    pub remote_path: Result<String>,
+}
+
+/// Classifies every file with a wanted extension the way a pass would: left
+/// out by a metadata filter, already in the upload log, or to be uploaded, and
+/// where to. Files are hashed to look them up in the log, `max_concurrent_uploads`
+/// at a time, but nothing is uploaded, renamed or written.
+///
+/// Files to upload come first, in `order_by` order, then the skipped ones.
+pub async fn list(config: &Config) -> Result<Vec<ListedFile>> {
+    // Read-only, so a running backup is not disturbed.
+    let config = &Config { dry_run: true, ..config.clone() };
+    let upload_log = UploadLog::open(config)?;
+    let upload_log = &upload_log;
+    let Scan { files, skipped } = scan_async(config).await?;
+    let mut listed = stream::iter(files)
+        .map(|path| async move {
+            let skip = match content_hash_async(&path).await {
+                Ok(hash) => upload_log
+                    .contains(&path, &hash)?
+                    .then_some(SkipReason::AlreadyUploaded),
+                Err(_) => Some(SkipReason::Unreadable),
+            };
+            Ok(ListedFile {
+                size: path.metadata().ok().map(|metadata| metadata.len()),
+                remote_path: dropbox_path(config, &path),
+                skip,
+                path,
+            })
+        })
+        .buffered(config.max_concurrent_uploads)
+        .collect::<Vec<Result<_>>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    // Files to upload first, keeping the scan's order within each group.
+    listed.sort_by_key(|file| file.skip.is_some());
+    listed.extend(skipped.into_iter().map(|(path, reason)| ListedFile {
+        size: path.metadata().ok().map(|metadata| metadata.len()),
+        remote_path: dropbox_path(config, &path),
+        skip: Some(reason),
+        path,
+    }));
+    Ok(listed)
+}
@@ -0,0 +1,58 @@
+//! Keeping two instances from working on the same upload log at once.
+
+use anyhow::{Context, Result, anyhow};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+
+use crate::config::Config;
+
+/// An exclusive advisory lock on `lock_file`, held until this is dropped or the
+/// process exits, however it exits.
+///
+/// The file itself is left in place: removing it would let a later instance
+/// lock a new file while an older one still holds the removed one.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Takes the lock without waiting, failing with the holder's process id if
+    /// another instance has it.
+    pub fn acquire(config: &Config) -> Result<Self> {
+        let path = &config.lock_file;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).ok();
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Opening lock file {:?}", path))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(anyhow!(
+                    "Another instance{} is already running (lock file {:?})",
+                    holder(path)
+                        .map(|pid| format!(" (pid {})", pid))
+                        .unwrap_or_default(),
+                    path
+                ));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Locking {:?}", path));
+            }
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+fn holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,263 @@
+//! Log output for the command line: human-readable lines, colored on a
+//! terminal unless `NO_COLOR` is set, or one JSON object per line for log
+//! shippers. `LOG_FILE` also keeps a copy in a size-rotated file.
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{SecondsFormat, Utc};
+use env_logger::fmt::{Formatter, style::Style};
+use env_logger::{Env, Target, WriteStyle};
+use log::kv::{self, VisitSource};
+use log::{Log, Metadata, Record};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::config::{ByteSize, Settings};
+use crate::progress;
+
+/// Selected by `LOG_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// `{"timestamp", "level", "target", "message", ...}`, plus the record's
+    /// key-values (such as `path`, `size`, `duration_ms` and `mb_per_sec` of an
+    /// upload) as further fields.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(anyhow!("expected text or json, got `{}`", other)),
+        }
+    }
+}
+
+const DEFAULT_LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_FILE_KEEP: usize = 5;
+
+/// Installs the global logger in the format `LOG_FORMAT` selects, filtered by
+/// `RUST_LOG` (default `default_filter`). Text output to a terminal also turns
+/// on the progress bars, which log lines are then printed around.
+///
+/// With `LOG_FILE` set the same lines, without color, are appended to that
+/// file too, which is rotated once it reaches `LOG_FILE_MAX_SIZE`.
+pub fn init(settings: &Settings, default_filter: &str) -> Result<()> {
+    let format = settings.parse("LOG_FORMAT", LogFormat::Text)?;
+    let builder = || {
+        let mut builder = env_logger::Builder::from_env(
+            Env::default().default_filter_or(default_filter),
+        );
+        match format {
+            LogFormat::Text => builder.format(write_text),
+            LogFormat::Json => builder.format(write_json),
+        };
+        builder
+    };
+    let logger = builder().build();
+    // Parsed even without `LOG_FILE`, so a bad value is reported either way.
+    let ByteSize(max_size) =
+        settings.parse("LOG_FILE_MAX_SIZE", ByteSize(DEFAULT_LOG_FILE_MAX_SIZE))?;
+    let keep = settings.parse("LOG_FILE_KEEP", DEFAULT_LOG_FILE_KEEP)?;
+    let file = match settings.parse_opt::<PathBuf>("LOG_FILE")? {
+        Some(path) => {
+            let writer = RotatingFile::open(path, max_size, keep)?;
+            Some(
+                builder()
+                    .target(Target::Pipe(Box::new(writer)))
+                    .write_style(WriteStyle::Never)
+                    .build(),
+            )
+        }
+        None => None,
+    };
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(AroundProgress { logger, file }))?;
+    if format == LogFormat::Text
+        && io::stdout().is_terminal()
+        && io::stderr().is_terminal()
+    {
+        progress::enable();
+    }
+    Ok(())
+}
+
+/// Clears the progress bars while a line is written, and copies it to the
+/// log file, if any.
+struct AroundProgress {
+    logger: env_logger::Logger,
+    file: Option<env_logger::Logger>,
+}
+
+impl Log for AroundProgress {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.logger.matches(record) {
+            progress::suspend(|| self.logger.log(record));
+            if let Some(file) = &self.file {
+                file.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+        if let Some(file) = &self.file {
+            file.flush();
+        }
+    }
+}
+
+/// Appends to `path` and, once it holds `max_size` bytes, renames it to
+/// `path.1` (shifting older ones to `path.2` and so on, up to `path.<keep>`)
+/// and starts a new one. The check happens when a line has been flushed, so
+/// lines are never split across files.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = Self::append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_size, keep, file, size })
+    }
+
+    fn append(path: &Path) -> Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Open log file {:?}", path))
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = Self::append(&self.path).map_err(io::Error::other)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.size >= self.max_size {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory that text lines about a file name it relative to.
+static BASE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Makes text lines about a file start with its path relative to `dir`,
+/// usually `current_directory`, instead of the full path. Only the first call
+/// has an effect.
+pub fn set_base_dir(dir: &Path) {
+    let _ = BASE_DIR.set(dir.to_path_buf());
+}
+
+/// `<timestamp> <LEVEL> [<target>:] [<file>:] <message>`. The target is left out
+/// for our own lines, and lines about a file (those with a `path` key) lead
+/// with its name.
+fn write_text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let dim = Style::new().dimmed();
+    let level = buf.default_level_style(record.level());
+    write!(
+        buf,
+        "{dim}{}{dim:#} {level}{:<5}{level:#} ",
+        buf.timestamp_seconds(),
+        record.level()
+    )?;
+    if !record.target().starts_with(env!("CARGO_CRATE_NAME")) {
+        write!(buf, "{dim}{}:{dim:#} ", record.target())?;
+    }
+    if let Some(path) = record.key_values().get(kv::Key::from_str("path")) {
+        let path = PathBuf::from(path.to_string());
+        let shown = BASE_DIR
+            .get()
+            .and_then(|base| path.strip_prefix(base).ok())
+            .unwrap_or(&path);
+        let bold = Style::new().bold();
+        write!(buf, "{bold}{}:{bold:#} ", shown.display())?;
+    }
+    writeln!(buf, "{}", record.args())
+}
+
+fn write_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "timestamp".into(),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into(),
+    );
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    record.key_values().visit(&mut Fields(&mut line)).map_err(io::Error::other)?;
+    serde_json::to_writer(&mut *buf, &line)?;
+    writeln!(buf)
+}
+
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(
+        &mut self,
+        key: kv::Key<'kvs>,
+        value: kv::Value<'kvs>,
+    ) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(n) = value.to_f64() {
+            serde_json::Number::from_f64(n).map_or(serde_json::Value::Null, Into::into)
+        } else if let Some(b) = value.to_bool() {
+            b.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
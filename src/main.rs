@@ -1,331 +1,248 @@
 use anyhow::{Context, Result, anyhow};
-use dotenvy::dotenv;
-use env_logger::Env;
-use log::{error, info, warn};
-use reqwest::StatusCode;
-use serde::Deserialize;
-use std::collections::HashSet;
-use std::env;
-use std::fs::{self, File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use clap::{Parser, Subcommand};
+use fs_library::{
+    Config, EncryptionKey, InstanceLock, ListedFile, Settings, SkipReason,
+};
+use log::info;
 use std::path::{Path, PathBuf};
-use tokio::fs as tokio_fs;
-use tokio::io::AsyncReadExt;
-use walkdir::WalkDir;
 
-#[derive(Debug, Clone)]
-struct Config {
-    api_key: Option<String>,
-    api_address: String,
-    api_refresh_address: String,
-    dropbox_path: Option<String>,
-    app_key: String,
-    app_secret: String,
-    refresh_token: String,
-    dropbox_dir: String,
-    uploaded_files_log: PathBuf,
-    uploaded_directory: PathBuf,
-    current_directory: PathBuf,
-    file_extensions: Vec<String>,
+/// Command-line options. Each one overrides the env var named in its help.
+#[derive(Debug, Parser)]
+#[command(version, about = "Back up local files to Dropbox")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// TOML config file; env vars and flags override its values [CONFIG_FILE]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Dotenv file to load instead of ./.env [ENV_FILE]
+    #[arg(long, value_name = "FILE")]
+    env_file: Option<PathBuf>,
+    /// Directory to scan for files [CURRENT_DIRECTORY]
+    #[arg(long, value_name = "DIR")]
+    current_dir: Option<PathBuf>,
+    /// Dropbox folder to upload into [DROPBOX_DIR]
+    #[arg(long, value_name = "PATH")]
+    dropbox_dir: Option<String>,
+    /// Local folder uploaded files are moved to [UPLOADED_DIRECTORY]
+    #[arg(long, value_name = "DIR")]
+    uploaded_dir: Option<PathBuf>,
+    /// Log of already uploaded files [UPLOADED_FILES_LOG]
+    #[arg(long, value_name = "FILE")]
+    uploaded_log: Option<PathBuf>,
+    /// Comma-separated extensions to upload [FILE_EXTENSIONS]
+    #[arg(long, value_name = "LIST")]
+    extensions: Option<String>,
+    /// Comma-separated directory names to skip [SKIP_DIRS]
+    #[arg(long, value_name = "LIST")]
+    skip_dirs: Option<String>,
+    /// Scan subdirectories too [RECURSE]
+    #[arg(long)]
     recurse: bool,
-    skip_dirs: HashSet<String>,
-    short_token_file: PathBuf,
+    /// Mirror local subdirectories under the Dropbox folder [PRESERVE_TREE]
+    #[arg(long)]
+    preserve_tree: bool,
+    /// Number of files uploaded at the same time [MAX_CONCURRENT_UPLOADS]
+    #[arg(long, value_name = "N")]
+    max_concurrent_uploads: Option<usize>,
+    /// add, overwrite or update [UPLOAD_MODE]
+    #[arg(long, value_name = "MODE")]
+    upload_mode: Option<String>,
+    /// Report what would happen without changing anything [DRY_RUN]
+    #[arg(long)]
+    dry_run: bool,
+    /// Stop starting new uploads after the first failure [FAIL_FAST]
+    #[arg(long)]
+    fail_fast: bool,
+    /// Keep running and upload matching files as they appear [WATCH]
+    #[arg(long)]
+    watch: bool,
+    /// Re-run every N seconds instead of exiting [POLL_INTERVAL_SECS]
+    #[arg(long, value_name = "SECS")]
+    poll_interval: Option<u64>,
 }
 
-impl Config {
-    fn from_env() -> Result<Self> {
-        dotenv().ok();
-        let get =
-            |k: &str| env::var(k).with_context(|| format!("Missing env var `{}`", k));
-
-        let api_key = env::var("API_KEY").ok();
-        let dropbox_path = env::var("DROPBOX_PATH").ok();
-        let api_address = get("API_ADDRESS")?;
-        let api_refresh_address = get("API_REFRESH_ADDRESS")?;
-        let app_key = get("APP_KEY")?;
-        let app_secret = get("APP_SECRET")?;
-        let refresh_token = get("REFRESH_TOKEN")?;
-        let dropbox_dir = get("DROPBOX_DIR")?;
-        let uploaded_files_log = PathBuf::from(get("UPLOADED_FILES_LOG")?);
-        let uploaded_directory = PathBuf::from(get("UPLOADED_DIRECTORY")?);
-        let current_directory = PathBuf::from(get("CURRENT_DIRECTORY")?);
-        let file_extensions = env::var("FILE_EXTENSIONS")?
-            .split(',')
-            .map(|s| s.trim().to_string())
-            .filter(|s| !s.is_empty())
-            .collect::<Vec<_>>();
-        let recurse = env::var("RECURSE")
-            .map(|v| matches!(v.to_lowercase().as_str(), "true" | "1" | "t"))
-            .unwrap_or(false);
-        let skip_dirs = env::var("SKIP_DIRS")
-            .unwrap_or_default()
-            .split(',')
-            .filter(|s| !s.trim().is_empty())
-            .map(|s| s.trim().to_string())
-            .collect::<HashSet<_>>();
-        let short_token_file = PathBuf::from(get("SHORT_TOKEN_FILE")?);
-
-        Ok(Self {
-            api_key,
-            api_address,
-            api_refresh_address,
-            dropbox_path,
-            app_key,
-            app_secret,
-            refresh_token,
-            dropbox_dir,
-            uploaded_files_log,
-            uploaded_directory,
-            current_directory,
-            file_extensions,
-            recurse,
-            skip_dirs,
-            short_token_file,
-        })
-    }
-}
-
-fn ensure_log_exists(path: &Path) -> Result<()> {
-    if !path.exists() {
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        File::create(path)?;
-    }
-    Ok(())
-}
-
-fn check_uploaded_log(log_path: &Path, file_path: &Path) -> Result<bool> {
-    ensure_log_exists(log_path)?;
-    let f = File::open(log_path)?;
-    let reader = BufReader::new(f);
-    for line in reader.lines() {
-        if line? == file_path.to_string_lossy() {
-            return Ok(true);
-        }
-    }
-    Ok(false)
-}
-
-fn log_uploaded_file(log_path: &Path, file_path: &Path) -> Result<()> {
-    ensure_log_exists(log_path)?;
-    let mut f = OpenOptions::new().append(true).create(true).open(log_path)?;
-    writeln!(f, "{}", file_path.to_string_lossy())?;
-    Ok(())
-}
-
-fn extract_filename(path: &Path) -> Result<String> {
-    Ok(path
-        .file_name()
-        .ok_or_else(|| anyhow!("No filename in path"))?
-        .to_string_lossy()
-        .to_string())
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Decrypt a file downloaded from an encrypted backup, using the configured
+    /// key [ENCRYPTION_KEY or ENCRYPTION_KEY_FILE]
+    Decrypt {
+        /// The downloaded `.enc` file
+        input: PathBuf,
+        /// Where to write the plaintext; defaults to INPUT without `.enc`
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Check that every file in the upload log exists remotely, and upload the
+    /// missing ones again from the uploaded directory
+    Verify,
+    /// List the files a backup pass would find, with their size, whether they
+    /// would be uploaded or skipped and their Dropbox path, without uploading
+    List,
+    /// Have Dropbox download the URLs listed in MANIFEST, one `<url> [<name>]`
+    /// per line, into the Dropbox folder
+    SaveUrl {
+        /// The list of URLs
+        manifest: PathBuf,
+    },
+    /// Manage the Dropbox credentials
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
 }
 
-fn move_file(source: &Path, destination_dir: &Path) -> Result<()> {
-    fs::create_dir_all(destination_dir)?;
-    let dest =
-        destination_dir.join(source.file_name().ok_or_else(|| anyhow!("No filename"))?);
-    fs::rename(source, &dest)
-        .with_context(|| format!("Failed to move {:?} to {:?}", source, dest))?;
-    Ok(())
+#[derive(Debug, Subcommand)]
+enum AuthCommand {
+    /// Authorize the app [APP_KEY] in the browser and save the refresh token as
+    /// REFRESH_TOKEN in the env file
+    Login {
+        /// The dotenv file to write; defaults to ENV_FILE, or else .env
+        #[arg(long, value_name = "FILE")]
+        env_file: Option<PathBuf>,
+        /// Local port for the redirect; http://127.0.0.1:PORT/callback must be
+        /// a redirect URI of the app
+        #[arg(long, default_value_t = 53682)]
+        port: u16,
+    },
 }
 
-fn sanitize_filename_spaces(path: &Path) -> Result<PathBuf> {
-    let file_name =
-        path.file_name().ok_or_else(|| anyhow!("No file name"))?.to_string_lossy();
-    if !file_name.contains(' ') {
-        return Ok(path.to_path_buf());
-    }
-    let new_name = file_name.replace(' ', "_");
-    let new_path = path.parent().unwrap_or_else(|| Path::new("")).join(new_name);
-    fs::rename(path, &new_path)?;
-    info!("Renamed file: {:?} -> {:?}", path, new_path);
-    Ok(new_path)
-}
-
-fn collect_files(config: &Config) -> Result<Vec<PathBuf>> {
-    let mut files = Vec::new();
-    let exts: HashSet<String> =
-        config.file_extensions.iter().map(|e| e.to_lowercase()).collect();
-
-    let walker = if config.recurse {
-        WalkDir::new(&config.current_directory)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .collect::<Vec<_>>()
-    } else {
-        fs::read_dir(&config.current_directory)?
-            .filter_map(|e| e.ok())
-            .map(|e| WalkDir::new(e.path()).into_iter().next().unwrap().unwrap())
-            .collect()
-    };
-
-    for entry in walker {
-        let path = entry.path();
-        if entry.file_type().is_dir() {
-            if config
-                .skip_dirs
-                .contains(&entry.file_name().to_string_lossy().to_string())
-            {
-                continue;
-            }
-            continue;
-        }
-        if let Some(ext) = path.extension().and_then(|s| s.to_str()) {
-            if exts.contains(&format!(".{}", ext.to_lowercase()))
-                || exts.contains(&ext.to_lowercase())
-            {
-                let sanitized = sanitize_filename_spaces(path)?;
-                files.push(sanitized);
-            }
-        }
+impl Cli {
+    fn settings(&self) -> Result<Settings> {
+        let path = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string());
+        let flag = |on: bool| on.then(|| "true".to_string());
+        let overrides = [
+            ("CURRENT_DIRECTORY", path(&self.current_dir)),
+            ("DROPBOX_DIR", self.dropbox_dir.clone()),
+            ("UPLOADED_DIRECTORY", path(&self.uploaded_dir)),
+            ("UPLOADED_FILES_LOG", path(&self.uploaded_log)),
+            ("FILE_EXTENSIONS", self.extensions.clone()),
+            ("SKIP_DIRS", self.skip_dirs.clone()),
+            ("RECURSE", flag(self.recurse)),
+            ("PRESERVE_TREE", flag(self.preserve_tree)),
+            (
+                "MAX_CONCURRENT_UPLOADS",
+                self.max_concurrent_uploads.map(|n| n.to_string()),
+            ),
+            ("UPLOAD_MODE", self.upload_mode.clone()),
+            ("DRY_RUN", flag(self.dry_run)),
+            ("FAIL_FAST", flag(self.fail_fast)),
+            ("WATCH", flag(self.watch)),
+            ("POLL_INTERVAL_SECS", self.poll_interval.map(|n| n.to_string())),
+            ("CONFIG_FILE", path(&self.config)),
+            ("ENV_FILE", path(&self.env_file)),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?)))
+        .collect();
+        Settings::load(overrides)
     }
-    Ok(files)
 }
 
-async fn read_short_token_or_create(config: &Config) -> Result<String> {
-    if config.short_token_file.exists() {
-        let mut f = tokio_fs::File::open(&config.short_token_file).await?;
-        let mut buf = String::new();
-        f.read_to_string(&mut buf).await?;
-        return Ok(buf.trim().to_string());
-    }
-
-    warn!("short_token.txt not found, requesting new token...");
-    let token = get_new_short_token(config).await?;
-    write_short_token(&config.short_token_file, &token).await?;
-    Ok(token)
-}
-
-async fn write_short_token(path: &Path, token: &str) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        tokio_fs::create_dir_all(parent).await.ok();
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let settings = cli.settings()?;
+    fs_library::logging::init(&settings, "info")?;
+    if let Some(Command::Decrypt { input, output }) = &cli.command {
+        return decrypt(&settings, input, output.as_deref());
     }
-    tokio_fs::write(path, token)
-        .await
-        .with_context(|| format!("Write short token file: {:?}", path))
-}
-
-async fn get_new_short_token(config: &Config) -> Result<String> {
-    #[derive(Deserialize)]
-    struct Resp {
-        access_token: String,
+    if let Some(Command::Auth { command: AuthCommand::Login { env_file, port } }) =
+        &cli.command
+    {
+        let env_file =
+            env_file.as_deref().or(settings.env_file()).unwrap_or(Path::new(".env"));
+        return fs_library::auth::login(&settings, env_file, *port).await;
     }
-
-    info!("Requesting new short-lived access token...");
-    let client = reqwest::Client::new();
-    let resp = client
-        .post(&config.api_refresh_address)
-        .form(&[
-            ("refresh_token", config.refresh_token.as_str()),
-            ("grant_type", "refresh_token"),
-            ("client_id", config.app_key.as_str()),
-            ("client_secret", config.app_secret.as_str()),
-        ])
-        .send()
-        .await
-        .context("Token refresh request failed")?;
-
-    if !resp.status().is_success() {
-        return Err(anyhow!("Token refresh HTTP {}", resp.status()));
+    let config = Config::from_settings(&settings)?;
+    fs_library::logging::set_base_dir(&config.current_directory);
+    // Reads only, so it may run next to a backup holding the lock.
+    if let Some(Command::List) = &cli.command {
+        print_list(&config, &fs_library::list(&config).await?);
+        return Ok(());
     }
-
-    let body: Resp = resp.json().await.context("Parsing token refresh JSON")?;
-    Ok(body.access_token)
-}
-
-async fn upload_file_once(
-    client: &reqwest::Client,
-    config: &Config,
-    local_file: &Path,
-    short_token: &str,
-) -> Result<()> {
-    let path_arg = format!("{}/{}", config.dropbox_dir, extract_filename(local_file)?);
-    let dropbox_arg = serde_json::json!({
-        "autorename": false,
-        "mode": "add",
-        "mute": false,
-        "path": path_arg,
-        "strict_conflict": false,
-    });
-
-    let mut file = tokio_fs::File::open(local_file).await?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf).await?;
-
-    let req = client
-        .post(&config.api_address)
-        .header("Authorization", format!("Bearer {}", short_token))
-        .header("Content-Type", "application/octet-stream")
-        .header("Dropbox-API-Arg", dropbox_arg.to_string())
-        .body(buf);
-
-    let resp = req.send().await?;
-    match resp.status() {
-        s if s.is_success() => {
-            info!("Uploaded {:?} successfully (HTTP {})", local_file, s);
-            Ok(())
+    let _lock = InstanceLock::acquire(&config)?;
+    if let Some(Command::Verify) = &cli.command {
+        let summary = fs_library::verify(&config).await?;
+        if !summary.lost.is_empty() || !summary.failed.is_empty() {
+            return Err(anyhow!(
+                "{} file(s) lost, {} failed",
+                summary.lost.len(),
+                summary.failed.len()
+            ));
         }
-        StatusCode::UNAUTHORIZED => Err(anyhow!("unauthorized")),
-        s => {
-            let text = resp.text().await.unwrap_or_default();
-            Err(anyhow!("Upload failed: HTTP {} - {}", s, text))
-        }
-    }
-}
-
-async fn send_file(config: &Config, local_file: &Path) -> Result<()> {
-    if check_uploaded_log(&config.uploaded_files_log, local_file)? {
-        info!("Already uploaded, skipping: {:?}", local_file);
         return Ok(());
     }
-
-    let client = reqwest::Client::new();
-
-    let mut token = read_short_token_or_create(config).await?;
-    match upload_file_once(&client, config, local_file, &token).await {
-        Ok(()) => {
-            log_uploaded_file(&config.uploaded_files_log, local_file)?;
-            move_file(local_file, &config.uploaded_directory)?;
-            Ok(())
+    if let Some(Command::SaveUrl { manifest }) = &cli.command {
+        let summary = fs_library::save_urls(&config, manifest).await?;
+        if !summary.failed.is_empty() {
+            return Err(anyhow!("{} URL(s) failed", summary.failed.len()));
         }
-        Err(e) if e.to_string().contains("unauthorized") => {
-            warn!("Token expired/unauthorized. Refreshing...");
-            token = get_new_short_token(config).await?;
-            write_short_token(&config.short_token_file, &token).await?;
-            upload_file_once(&client, config, local_file, &token).await?;
-            log_uploaded_file(&config.uploaded_files_log, local_file)?;
-            move_file(local_file, &config.uploaded_directory)?;
-            Ok(())
-        }
-        Err(e) => Err(e),
+        return Ok(());
     }
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
-    let config = Config::from_env()?;
 
     info!("Starting Dropbox backup service");
-
-    fs::create_dir_all(&config.uploaded_directory).ok();
-    ensure_log_exists(&config.uploaded_files_log).ok();
-
-    let files = collect_files(&config)?;
-
-    if files.is_empty() {
-        info!("No files matched the provided extensions.");
-        return Ok(());
+    if let Some(addr) = config.metrics_addr {
+        fs_library::metrics::serve(addr).await?;
+    }
+    if config.watch {
+        return fs_library::watch(&config).await;
     }
+    if let Some(interval) = config.poll_interval {
+        return fs_library::poll(&config, interval).await;
+    }
+    let summary = fs_library::run(&config).await?;
+    if !summary.failed.is_empty() {
+        return Err(anyhow!("{} file(s) failed", summary.failed.len()));
+    }
+    Ok(())
+}
 
+/// One line per file: what a pass would do with it, its size in bytes, and its
+/// path relative to `current_directory` with the Dropbox path it maps to.
+fn print_list(config: &Config, files: &[ListedFile]) {
+    // As wide as the longest skip reason.
+    println!("{:<21}  {:>12}  PATH -> DROPBOX PATH", "STATUS", "SIZE");
     for file in files {
-        if let Err(e) = send_file(&config, &file).await {
-            error!("Failed to process {:?}: {}", file, e);
-        }
+        let size = file.size.map_or_else(|| "-".to_string(), |size| size.to_string());
+        let path =
+            file.path.strip_prefix(&config.current_directory).unwrap_or(&file.path);
+        let remote_path = match &file.remote_path {
+            Ok(remote_path) => remote_path.clone(),
+            Err(e) => format!("(cannot upload: {})", e),
+        };
+        println!(
+            "{:<21}  {:>12}  {} -> {}",
+            file.skip.map_or("upload", SkipReason::as_str),
+            size,
+            path.display(),
+            remote_path
+        );
     }
+    let count =
+        |reason: Option<SkipReason>| files.iter().filter(|f| f.skip == reason).count();
+    let to_upload = count(None);
+    let logged = count(Some(SkipReason::AlreadyUploaded));
+    println!(
+        "{} file(s): {} to upload, {} already uploaded, {} skipped by filters",
+        files.len(),
+        to_upload,
+        logged,
+        files.len() - to_upload - logged
+    );
+}
 
-    info!("Done.");
+fn decrypt(settings: &Settings, input: &Path, output: Option<&Path>) -> Result<()> {
+    let key = EncryptionKey::from_settings(settings)?
+        .context("Set ENCRYPTION_KEY or ENCRYPTION_KEY_FILE to decrypt")?;
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None if input.extension().is_some_and(|ext| ext == "enc") => {
+            input.with_extension("")
+        }
+        None => return Err(anyhow!("{:?} does not end in .enc; pass --output", input)),
+    };
+    fs_library::decrypt_file(&key, input, &output)?;
+    info!("Decrypted {:?} -> {:?}", input, output);
     Ok(())
 }
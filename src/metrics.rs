@@ -0,0 +1,143 @@
+//! Counters for Prometheus, served over HTTP at `/metrics` when `METRICS_ADDR`
+//! is set.
+//!
+//! The counters are kept for the whole process, whether or not anything serves
+//! them, and add up over all passes of `watch` and `poll`.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+static FILES_UPLOADED: AtomicU64 = AtomicU64::new(0);
+static BYTES_UPLOADED: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn uploaded(bytes: u64) {
+    FILES_UPLOADED.fetch_add(1, Ordering::Relaxed);
+    BYTES_UPLOADED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn failed() {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn retried() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Files of the current pass that are not done yet.
+pub(crate) fn set_queue_depth(files: usize) {
+    QUEUE_DEPTH.store(files as u64, Ordering::Relaxed);
+}
+
+/// A file joined the watch queue.
+pub(crate) fn queued() {
+    QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A file from the watch queue is done.
+pub(crate) fn dequeued() {
+    QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// The counters in the Prometheus text format.
+pub fn render() -> String {
+    let metrics = [
+        (
+            "fs_library_files_uploaded_total",
+            "counter",
+            "Files uploaded.",
+            &FILES_UPLOADED,
+        ),
+        (
+            "fs_library_bytes_uploaded_total",
+            "counter",
+            "Bytes of the files uploaded.",
+            &BYTES_UPLOADED,
+        ),
+        (
+            "fs_library_failures_total",
+            "counter",
+            "Files that could not be uploaded.",
+            &FAILURES,
+        ),
+        (
+            "fs_library_retries_total",
+            "counter",
+            "API calls retried after a transient failure.",
+            &RETRIES,
+        ),
+        (
+            "fs_library_queue_depth",
+            "gauge",
+            "Files of the current pass still to be processed.",
+            &QUEUE_DEPTH,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+    }
+    out
+}
+
+/// Listens on `addr` and answers `GET /metrics` with [`render`] in the
+/// background for as long as the runtime lives. Binding happens before this
+/// returns, so an address in use is an error here rather than later.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Listen for metrics on {}", addr))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(answer(stream));
+                }
+                Err(e) => warn!("Could not accept a metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn answer(mut stream: TcpStream) {
+    let mut buf = vec![0; 8192];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => len += n,
+        }
+    }
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|rest| rest.split(' ').next());
+    let (status, content_type, body) = match target {
+        Some(target) if target.split('?').next() == Some("/metrics") => {
+            ("200 OK", "text/plain; version=0.0.4; charset=utf-8", render())
+        }
+        _ => ("404 Not Found", "text/plain; charset=utf-8", "Not found".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    // A scraper going away is its own problem.
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,95 @@
+//! Reporting the outcome of a backup pass to webhooks.
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::Serialize;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::http::build_http_client;
+use crate::{RunStats, RunSummary};
+
+/// Body of the webhook POST.
+#[derive(Serialize)]
+struct Report {
+    /// `success`, `failure` when some files failed or were not attempted, or
+    /// `error` when the pass could not run at all.
+    status: &'static str,
+    #[serde(flatten)]
+    stats: Option<RunStats>,
+    /// Only set for `error`; otherwise part of the stats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<f64>,
+    errors: Vec<ErrorReport>,
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    error: String,
+}
+
+impl Report {
+    fn new(result: &Result<RunSummary>, elapsed: Duration) -> Self {
+        match result {
+            Ok(summary) => Self {
+                // Files are only left out without a failure at the byte limit.
+                status: if summary.failed.is_empty() { "success" } else { "failure" },
+                stats: Some(summary.stats()),
+                duration_secs: None,
+                errors: summary
+                    .failed
+                    .iter()
+                    .map(|(path, e)| ErrorReport {
+                        path: Some(path.display().to_string()),
+                        error: format!("{:#}", e),
+                    })
+                    .collect(),
+            },
+            Err(e) => Self {
+                status: "error",
+                stats: None,
+                duration_secs: Some(elapsed.as_secs_f64()),
+                errors: vec![ErrorReport { path: None, error: format!("{:#}", e) }],
+            },
+        }
+    }
+}
+
+/// Sends the report of a pass to `notify_webhook_url` and, unless it fully
+/// succeeded, to `notify_failure_webhook_url`. A webhook that fails or does not
+/// answer within `notify_timeout` is only logged, without its URL, which often
+/// embeds a secret.
+pub(crate) async fn run_finished(
+    config: &Config,
+    result: &Result<RunSummary>,
+    elapsed: Duration,
+) {
+    let report = Report::new(result, elapsed);
+    let failure_url = config
+        .notify_failure_webhook_url
+        .as_deref()
+        .filter(|_| report.status != "success");
+    let webhooks =
+        [("Completion", config.notify_webhook_url.as_deref()), ("Failure", failure_url)];
+    for (name, url) in webhooks {
+        let Some(url) = url else { continue };
+        match post(config, url, &report).await {
+            Ok(()) => info!("{} webhook notified ({})", name, report.status),
+            Err(e) => warn!("{} webhook failed: {:#}", name, e),
+        }
+    }
+}
+
+async fn post(config: &Config, url: &str, report: &Report) -> Result<()> {
+    build_http_client(config)?
+        .post(url)
+        .json(report)
+        .timeout(config.notify_timeout)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.without_url())?;
+    Ok(())
+}
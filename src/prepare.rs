@@ -0,0 +1,160 @@
+//! Compressing and encrypting files before upload.
+
+use anyhow::{Context, Result};
+use flate2::GzBuilder;
+use flate2::write::GzEncoder;
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Compression, Config};
+use crate::encrypt::EncryptWriter;
+use crate::files::content_hash;
+
+/// A compressed and/or encrypted copy of a file, written next to the upload
+/// session files and removed again when dropped.
+#[derive(Debug)]
+pub(crate) struct Prepared {
+    pub path: PathBuf,
+    /// Dropbox content hash of the prepared bytes.
+    pub content_hash: String,
+}
+
+impl Drop for Prepared {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            warn!("Could not remove prepared copy {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Suffix appended to Dropbox paths for what `config` does to the bytes, e.g.
+/// `.gz.enc`.
+pub(crate) fn remote_suffix(config: &Config) -> String {
+    let mut suffix = config.compress.extension().to_string();
+    if config.encryption_key.is_some() {
+        suffix.push_str(".enc");
+    }
+    suffix
+}
+
+/// Writes the bytes to upload for `local_file` into `upload_session_dir`, as
+/// `name` followed by the remote suffix: gzipped if `compress` is set, then
+/// encrypted if an encryption key is. Returns `None` when neither is, and the
+/// file is uploaded as it is.
+///
+/// Everything is streamed in one pass. The gzip header carries no name or
+/// timestamp, so without encryption the same input always yields the same
+/// bytes and an interrupted chunked upload can resume on the next run.
+/// Encryption uses a fresh nonce each time, so only retries within a run can.
+pub(crate) async fn prepare(
+    config: &Config,
+    local_file: &Path,
+    name: &str,
+) -> Result<Option<Prepared>> {
+    let suffix = remote_suffix(config);
+    if suffix.is_empty() {
+        return Ok(None);
+    }
+    let source = local_file.to_path_buf();
+    let dir = config.upload_session_dir.clone();
+    let name = format!("{}{}", name, suffix);
+    let compress = config.compress;
+    let key = config.encryption_key.clone();
+    tokio::task::spawn_blocking(move || {
+        fs::create_dir_all(&dir)?;
+        let mut prepared =
+            Prepared { path: dir.join(name), content_hash: String::new() };
+        let mut input =
+            File::open(&source).with_context(|| format!("Open {:?}", source))?;
+        let output = BufWriter::new(File::create(&prepared.path)?);
+        let written = match &key {
+            Some(key) => {
+                let encrypted = EncryptWriter::new(key, output)?;
+                transform(&mut input, encrypted, compress)?.finish()?.into_inner()
+            }
+            None => transform(&mut input, output, compress)?.into_inner(),
+        };
+        written
+            .map_err(|e| e.into_error())
+            .and_then(|f| f.sync_all())
+            .with_context(|| format!("Write {:?}", prepared.path))?;
+        prepared.content_hash = content_hash(&prepared.path)?;
+        Ok(Some(prepared))
+    })
+    .await?
+}
+
+/// Copies `input` into `output`, gzipped if `compress` says so.
+fn transform<W: Write>(
+    input: &mut File,
+    mut output: W,
+    compress: Compression,
+) -> io::Result<W> {
+    match compress {
+        Compression::None => {
+            io::copy(input, &mut output)?;
+            Ok(output)
+        }
+        Compression::Gzip => {
+            let mut encoder: GzEncoder<W> =
+                GzBuilder::new().write(output, flate2::Compression::default());
+            io::copy(input, &mut encoder)?;
+            encoder.finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encrypt::decrypt_file;
+    use crate::test_util::{TempDir, config_in};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn gunzip(path: &Path) -> String {
+        let mut text = String::new();
+        GzDecoder::new(File::open(path).unwrap()).read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[tokio::test]
+    async fn same_input_gives_the_same_gzip_bytes() {
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[("COMPRESS", "gzip")]).unwrap();
+        let local_file = config.current_directory.join("a.txt");
+        let text = "hello ".repeat(1000);
+        fs::write(&local_file, &text).unwrap();
+
+        let first = prepare(&config, &local_file, "first").await.unwrap().unwrap();
+        let second = prepare(&config, &local_file, "second").await.unwrap().unwrap();
+        assert_eq!(first.path, config.upload_session_dir.join("first.gz"));
+        assert_eq!(first.content_hash, second.content_hash);
+        assert_eq!(gunzip(&first.path), text);
+
+        let path = first.path.clone();
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn encrypted_copy_decrypts_to_the_compressed_file() {
+        let dir = TempDir::new();
+        let key = "07".repeat(32);
+        let config =
+            config_in(&dir, &[("COMPRESS", "gzip"), ("ENCRYPTION_KEY", &key)]).unwrap();
+        let local_file = config.current_directory.join("a.txt");
+        fs::write(&local_file, "hello").unwrap();
+
+        let prepared = prepare(&config, &local_file, "a").await.unwrap().unwrap();
+        assert_eq!(prepared.path, config.upload_session_dir.join("a.gz.enc"));
+        let opened = dir.path().join("opened.gz");
+        let key = config.encryption_key.as_ref().unwrap();
+        decrypt_file(key, &prepared.path, &opened).unwrap();
+        assert_eq!(gunzip(&opened), "hello");
+    }
+}
@@ -0,0 +1,62 @@
+//! Progress bars on the terminal, for the file list of a pass and for each
+//! chunked upload.
+//!
+//! All bars share one [`MultiProgress`] that draws nothing until
+//! [`crate::logging::init`] enables it, so embedding programs and redirected or
+//! JSON output never see them.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::LazyLock;
+
+static BARS: LazyLock<MultiProgress> =
+    LazyLock::new(|| MultiProgress::with_draw_target(ProgressDrawTarget::hidden()));
+
+/// Starts drawing bars on stderr.
+pub(crate) fn enable() {
+    BARS.set_draw_target(ProgressDrawTarget::stderr());
+}
+
+/// Runs `f`, which writes to the terminal, with the bars cleared so the output
+/// does not end up in the middle of one.
+pub(crate) fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    BARS.suspend(f)
+}
+
+/// A bar that is removed from the terminal when dropped, finished or not.
+pub(crate) struct Bar(ProgressBar);
+
+impl Bar {
+    /// Files of a pass done out of `total`.
+    pub(crate) fn files(total: u64) -> Self {
+        let style =
+            ProgressStyle::with_template("{wide_bar} {pos}/{len} files {elapsed}")
+                .expect("valid progress template");
+        Self(BARS.add(ProgressBar::new(total).with_style(style)))
+    }
+
+    /// Bytes of `name` sent out of `total`, starting at `sent`.
+    pub(crate) fn bytes(name: String, total: u64, sent: u64) -> Self {
+        let style = ProgressStyle::with_template(
+            "{msg} {wide_bar} {bytes}/{total_bytes} {bytes_per_sec} {eta}",
+        )
+        .expect("valid progress template");
+        let bar = ProgressBar::new(total).with_style(style).with_message(name);
+        bar.set_position(sent);
+        Self(BARS.add(bar))
+    }
+
+    pub(crate) fn inc(&self, n: u64) {
+        self.0.inc(n);
+    }
+
+    pub(crate) fn set_position(&self, pos: u64) {
+        self.0.set_position(pos);
+    }
+}
+
+impl Drop for Bar {
+    fn drop(&mut self) {
+        self.0.finish_and_clear();
+        BARS.remove(&self.0);
+    }
+}
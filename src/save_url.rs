@@ -0,0 +1,219 @@
+//! Having Dropbox fetch files from URLs itself with `files/save_url`, so they
+//! never pass through this machine.
+
+use anyhow::{Context, Result, anyhow};
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn};
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::auth::TokenCache;
+use crate::backend::{AnyBackend, DropboxBackend};
+use crate::config::Config;
+use crate::dropbox::{DropboxClient, FileMetadata, SaveUrlJob, SaveUrlStatus};
+use crate::http::{UploadError, with_retries};
+
+/// How long to wait between asking a running job whether it is done.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a job may run before its URL is reported as failed. Dropbox gives
+/// up on downloads long before this, so a job still running is stuck.
+const JOB_MAX_WAIT: Duration = Duration::from_secs(60 * 60);
+
+/// A line of a manifest: the URL to fetch and where to store it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveUrlEntry {
+    pub url: String,
+    /// Destination path, `/`-separated and starting at `dropbox_dir`.
+    pub remote_path: String,
+}
+
+/// Results of a [`save_urls`] run, by remote path.
+#[derive(Debug, Default)]
+pub struct SaveUrlSummary {
+    pub saved: Vec<String>,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+impl SaveUrlSummary {
+    fn log(&self) {
+        info!("Save URL: {} saved, {} failed", self.saved.len(), self.failed.len());
+        for (path, e) in &self.failed {
+            error!("  {}: {}", path, e);
+        }
+    }
+}
+
+/// Reads a manifest of one `<url> [<name>]` per line, ignoring blank lines and
+/// those starting with `#`. The file is stored as `name` under `dropbox_dir`,
+/// which may contain `/` for subfolders; without one the last segment of the
+/// URL's path is used.
+pub fn read_manifest(config: &Config, manifest: &Path) -> Result<Vec<SaveUrlEntry>> {
+    let text = fs::read_to_string(manifest)
+        .with_context(|| format!("Read URL manifest {:?}", manifest))?;
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("{:?} line {}", manifest, number + 1);
+        let mut fields = line.split_whitespace();
+        let (Some(url), name, None) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(anyhow!("expected `<url> [<name>]`")).with_context(context);
+        };
+        let parsed = reqwest::Url::parse(url)
+            .ok()
+            .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+            .ok_or_else(|| anyhow!("`{}` is not an http or https URL", url))
+            .with_context(context)?;
+        let name = match name {
+            Some(name) => name.trim_start_matches('/'),
+            None => parsed
+                .path_segments()
+                .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+                .ok_or_else(|| anyhow!("no file name in `{}`; give one after it", url))
+                .with_context(context)?,
+        };
+        entries.push(SaveUrlEntry {
+            url: url.to_string(),
+            remote_path: format!(
+                "{}/{}",
+                config.dropbox_dir.trim_end_matches('/'),
+                name
+            ),
+        });
+    }
+    Ok(entries)
+}
+
+/// Has Dropbox download every URL in `manifest` (see [`read_manifest`]) to its
+/// destination, `max_concurrent_uploads` at a time, waiting for each job to
+/// finish. A URL that fails does not stop the others. In a dry run the
+/// entries are only listed.
+///
+/// Only the Dropbox backend can do this. Nothing is recorded in the upload log,
+/// which is about local files.
+pub async fn save_urls(config: &Config, manifest: &Path) -> Result<SaveUrlSummary> {
+    let entries = read_manifest(config, manifest)?;
+    if config.dry_run {
+        for entry in &entries {
+            info!("[dry-run] Would save {} -> {}", entry.url, entry.remote_path);
+        }
+        return Ok(SaveUrlSummary::default());
+    }
+    let AnyBackend::Dropbox(backend) = AnyBackend::connect(config).await? else {
+        return Err(anyhow!("Saving URLs needs BACKEND=dropbox"));
+    };
+    Ok(save_urls_with(&backend, config, entries).await)
+}
+
+pub(crate) async fn save_urls_with<C: DropboxClient + Sync>(
+    backend: &DropboxBackend<C>,
+    config: &Config,
+    entries: Vec<SaveUrlEntry>,
+) -> SaveUrlSummary {
+    info!("Saving {} URL(s)", entries.len());
+    let mut summary = SaveUrlSummary::default();
+    let mut jobs = stream::iter(entries)
+        .map(|entry| async move {
+            let result =
+                save_url(&backend.client, config, &backend.tokens, &entry).await;
+            (entry, result)
+        })
+        .buffer_unordered(config.max_concurrent_uploads);
+    while let Some((entry, result)) = jobs.next().await {
+        match result {
+            Ok(_) => {
+                info!(
+                    remote_path:% = entry.remote_path;
+                    "Saved {} -> {}", entry.url, entry.remote_path
+                );
+                summary.saved.push(entry.remote_path);
+            }
+            Err(e) => {
+                error!(
+                    remote_path:% = entry.remote_path;
+                    "Failed to save {}: {}", entry.url, e
+                );
+                summary.failed.push((entry.remote_path, e));
+            }
+        }
+    }
+    summary.log();
+    summary
+}
+
+/// Starts the job for `entry` and polls it until Dropbox has the file, for at
+/// most [`JOB_MAX_WAIT`].
+async fn save_url(
+    client: &impl DropboxClient,
+    config: &Config,
+    tokens: &TokenCache,
+    entry: &SaveUrlEntry,
+) -> Result<FileMetadata> {
+    let job = authorized(client, config, tokens, "Save URL", |token| async move {
+        client.save_url(config, &token, &entry.remote_path, &entry.url).await
+    })
+    .await?;
+    let async_job_id = match job {
+        SaveUrlJob::Complete(metadata) => return Ok(metadata),
+        SaveUrlJob::AsyncJobId { async_job_id } => async_job_id,
+    };
+    let started = Instant::now();
+    loop {
+        if started.elapsed() >= JOB_MAX_WAIT {
+            return Err(anyhow!(
+                "Dropbox was still fetching it after {} minutes",
+                JOB_MAX_WAIT.as_secs() / 60
+            ));
+        }
+        tokio::time::sleep(JOB_POLL_INTERVAL).await;
+        let status = authorized(client, config, tokens, "Check save URL job", |token| {
+            let async_job_id = &async_job_id;
+            async move { client.check_save_url_job(config, &token, async_job_id).await }
+        })
+        .await?;
+        match status {
+            SaveUrlStatus::InProgress => {}
+            SaveUrlStatus::Complete(metadata) => return Ok(metadata),
+            SaveUrlStatus::Failed { failed } => {
+                let reason = failed
+                    .get(".tag")
+                    .and_then(|tag| tag.as_str())
+                    .map_or_else(|| failed.to_string(), str::to_string);
+                return Err(anyhow!("Dropbox could not fetch it: {}", reason));
+            }
+        }
+    }
+}
+
+/// `op` with the current token and retries, and once more with a fresh token if
+/// that one is rejected.
+async fn authorized<T, F, Fut>(
+    client: &impl DropboxClient,
+    config: &Config,
+    tokens: &TokenCache,
+    what: &str,
+    op: F,
+) -> Result<T>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let token = tokens.get(client, config).await?;
+    let op = &op;
+    let attempt =
+        |token: String| with_retries(&config.retry, what, move || op(token.clone()));
+    match attempt(token.clone()).await {
+        Err(UploadError::Unauthorized(_)) => {
+            warn!("Token expired/unauthorized. Refreshing...");
+            let token = tokens.refresh(client, config, &token).await?;
+            Ok(attempt(token).await?)
+        }
+        result => Ok(result?),
+    }
+}
@@ -0,0 +1,588 @@
+//! Finding the local files that are due for upload.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::config::{Config, OrderBy};
+
+/// How far in the future a modified time may be, to allow for filesystems with
+/// coarse timestamps or slightly unsynchronized clocks, before it counts as
+/// clock skew.
+const MTIME_FUTURE_TOLERANCE_SECS: i64 = 60;
+
+/// Why a file that matched the scan's filters was not uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    /// Zero bytes long, with `SKIP_EMPTY_FILES` set.
+    Empty,
+    /// Below `MIN_FILE_SIZE`.
+    TooSmall,
+    /// Above `MAX_FILE_SIZE`.
+    TooLarge,
+    /// Outside `MODIFIED_AFTER`..`MODIFIED_BEFORE`.
+    ModifiedOutOfRange,
+    /// Modified in the future, usually a wrong clock.
+    FutureModified,
+    /// Modified less than `MIN_FILE_AGE` ago, so possibly still being written.
+    TooRecent,
+    /// Its metadata could not be read.
+    Unreadable,
+    /// The upload log already has its content hash.
+    AlreadyUploaded,
+    /// Dropbox already has the same content at its destination, with
+    /// `SKIP_IF_REMOTE_MATCHES` set.
+    RemoteMatch,
+    /// Only reported on, in a dry run.
+    DryRun,
+    /// Something else is at its destination and `ON_CONFLICT` is `skip`.
+    Conflict,
+}
+
+impl SkipReason {
+    /// Name used in the run statistics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Empty => "empty",
+            Self::TooSmall => "too_small",
+            Self::TooLarge => "too_large",
+            Self::ModifiedOutOfRange => "modified_out_of_range",
+            Self::FutureModified => "future_modified",
+            Self::TooRecent => "too_recent",
+            Self::Unreadable => "unreadable",
+            Self::AlreadyUploaded => "already_uploaded",
+            Self::RemoteMatch => "remote_match",
+            Self::DryRun => "dry_run",
+            Self::Conflict => "conflict",
+        }
+    }
+}
+
+/// Result of [`scan`].
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// The files due for upload, in `order_by` order.
+    pub files: Vec<PathBuf>,
+    /// Files with a wanted extension that a metadata filter left out.
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+}
+
+pub fn collect_files(config: &Config) -> Result<Vec<PathBuf>> {
+    Ok(scan(config)?.files)
+}
+
+/// Like [`collect_files`], also reporting the files left out by size, modified
+/// time or unreadable metadata.
+///
+/// With `recurse`, symlinks are followed only if `follow_symlinks` is set; a
+/// link back to one of its own ancestor directories is logged and not entered.
+/// Without it, a symlink directly in `current_directory` that points to a file
+/// is picked up like the file, whatever `follow_symlinks` says, and one that
+/// points to a directory is not entered.
+pub fn scan(config: &Config) -> Result<Scan> {
+    let candidates =
+        if config.recurse { walk_tree(config)? } else { list_directory(config)? };
+
+    let mut scan = Scan::default();
+    let mut files = Vec::new();
+    for (path, metadata) in candidates {
+        if !matches_filters(config, &path) {
+            continue;
+        }
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                warn!(path:% = path.display(); "Skipping {:?}: cannot read its metadata: {}", path, e);
+                scan.skipped.push((path, SkipReason::Unreadable));
+                continue;
+            }
+        };
+        match metadata_skip_reason(config, &path, &metadata) {
+            Some(reason) => scan.skipped.push((path, reason)),
+            None => files.push((path, metadata)),
+        }
+    }
+    sort_files(&mut files, config.order_by);
+    scan.files = dedup_files(files.into_iter().map(|(path, _)| path));
+    Ok(scan)
+}
+
+/// [`scan`] on a blocking thread, so walking a large tree does not hold up the
+/// runtime's other tasks, such as serving metrics or uploads already running.
+pub async fn scan_async(config: &Config) -> Result<Scan> {
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || scan(&config)).await?
+}
+
+/// Drops the files that are the same file as an earlier one under another
+/// path, e.g. reached through a symlink, so a run does not upload it twice. A
+/// path that cannot be canonicalized is kept; its upload will fail on its own.
+fn dedup_files(files: impl Iterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashMap::new();
+    files
+        .filter(|path| {
+            let Ok(canonical) = fs::canonicalize(path) else {
+                return true;
+            };
+            match seen.entry(canonical) {
+                Entry::Occupied(first) => {
+                    info!(
+                        path:% = path.display();
+                        "Skipping {:?}: same file as {:?}", path, first.get()
+                    );
+                    false
+                }
+                Entry::Vacant(slot) => {
+                    slot.insert(path.clone());
+                    true
+                }
+            }
+        })
+        .collect()
+}
+
+/// Puts `files` in `order_by` order, so runs upload them in the same order
+/// whatever order the directory listing came in.
+fn sort_files(files: &mut [(PathBuf, Metadata)], order_by: OrderBy) {
+    match order_by {
+        OrderBy::Name => files.sort_by(|(a, _), (b, _)| a.cmp(b)),
+        OrderBy::Size => files.sort_by(|(a, a_meta), (b, b_meta)| {
+            a_meta.len().cmp(&b_meta.len()).then_with(|| a.cmp(b))
+        }),
+        OrderBy::Mtime => files.sort_by(|(a, a_meta), (b, b_meta)| {
+            let (a_mtime, b_mtime) = (a_meta.modified().ok(), b_meta.modified().ok());
+            a_mtime.cmp(&b_mtime).then_with(|| a.cmp(b))
+        }),
+    }
+}
+
+/// Everything but directories under `current_directory`, to `max_depth`.
+/// Entries and directories that cannot be read are logged and left out; only
+/// `current_directory` itself being unreadable is an error.
+fn walk_tree(config: &Config) -> Result<Vec<(PathBuf, io::Result<Metadata>)>> {
+    let walker = WalkDir::new(&config.current_directory)
+        .follow_links(config.follow_symlinks)
+        .max_depth(config.max_depth.unwrap_or(usize::MAX))
+        .into_iter()
+        .filter_entry(|e| !(e.file_type().is_dir() && is_skipped_dir(config, e.path())));
+    let mut candidates = Vec::new();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if e.depth() == 0 => {
+                return Err(e)
+                    .with_context(|| format!("Reading {:?}", config.current_directory));
+            }
+            Err(e) => {
+                match (e.loop_ancestor(), e.path()) {
+                    (Some(ancestor), path) => warn!(
+                        "Not following {:?}: it links back to {:?}",
+                        path.unwrap_or(ancestor),
+                        ancestor
+                    ),
+                    // Without the path the error message repeats.
+                    (None, Some(path)) => match e.io_error() {
+                        Some(io) => {
+                            warn!(path:% = path.display(); "Skipping {:?}: {}", path, io)
+                        }
+                        None => {
+                            warn!(path:% = path.display(); "Skipping {:?}: {}", path, e)
+                        }
+                    },
+                    (None, None) => warn!("Skipping an entry: {}", e),
+                }
+                continue;
+            }
+        };
+        if !entry.file_type().is_dir() {
+            let metadata = entry.metadata().map_err(io::Error::from);
+            candidates.push((entry.into_path(), metadata));
+        }
+    }
+    Ok(candidates)
+}
+
+/// The entries directly in `current_directory` other than directories, with
+/// symlinks resolved. Entries that cannot be read are logged and left out.
+fn list_directory(config: &Config) -> Result<Vec<(PathBuf, io::Result<Metadata>)>> {
+    let mut candidates = Vec::new();
+    let entries = fs::read_dir(&config.current_directory)
+        .with_context(|| format!("Reading {:?}", config.current_directory))?;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping an entry of {:?}: {}", config.current_directory, e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        let metadata = fs::metadata(&path);
+        if metadata.as_ref().is_ok_and(|m| m.is_dir()) {
+            continue;
+        }
+        candidates.push((path, metadata));
+    }
+    Ok(candidates)
+}
+
+/// The checks on a matching file that need its metadata. Logs why a file is
+/// skipped.
+pub(crate) fn matches_metadata_filters(
+    config: &Config,
+    path: &Path,
+    metadata: &Metadata,
+) -> bool {
+    metadata_skip_reason(config, path, metadata).is_none()
+}
+
+fn metadata_skip_reason(
+    config: &Config,
+    path: &Path,
+    metadata: &Metadata,
+) -> Option<SkipReason> {
+    let size = metadata.len();
+    if size == 0 && config.skip_empty_files {
+        info!(path:% = path.display(); "Skipping {:?}: empty file", path);
+        return Some(SkipReason::Empty);
+    }
+    if size < config.min_file_size {
+        info!(
+            path:% = path.display();
+            "Skipping {:?}: {} bytes is below MIN_FILE_SIZE ({} bytes)",
+            path, size, config.min_file_size
+        );
+        return Some(SkipReason::TooSmall);
+    }
+    if size > config.max_file_size {
+        info!(
+            path:% = path.display();
+            "Skipping {:?}: {} bytes is above MAX_FILE_SIZE ({} bytes)",
+            path, size, config.max_file_size
+        );
+        return Some(SkipReason::TooLarge);
+    }
+    if config.modified_after.is_some()
+        || config.modified_before.is_some()
+        || !config.min_file_age.is_zero()
+    {
+        return modified_skip_reason(config, path, metadata);
+    }
+    None
+}
+
+fn modified_skip_reason(
+    config: &Config,
+    path: &Path,
+    metadata: &Metadata,
+) -> Option<SkipReason> {
+    let modified: DateTime<Utc> = match metadata.modified() {
+        Ok(modified) => modified.into(),
+        Err(e) => {
+            warn!(path:% = path.display(); "Skipping {:?}: cannot read its modified time: {}", path, e);
+            return Some(SkipReason::Unreadable);
+        }
+    };
+    let now = Utc::now();
+    if modified > now + chrono::Duration::seconds(MTIME_FUTURE_TOLERANCE_SECS) {
+        warn!(
+            path:% = path.display();
+            "Skipping {:?}: modified time {} is in the future, check the clock",
+            path,
+            rfc3339(modified)
+        );
+        return Some(SkipReason::FutureModified);
+    }
+    let age = (now - modified).to_std().unwrap_or_default();
+    if age < config.min_file_age {
+        info!(
+            path:% = path.display();
+            "Skipping {:?}: modified {}, less than MIN_FILE_AGE ({:?}) ago",
+            path,
+            rfc3339(modified),
+            config.min_file_age
+        );
+        return Some(SkipReason::TooRecent);
+    }
+    if let Some(after) = config.modified_after.map(|bound| bound.resolve(now))
+        && modified <= after
+    {
+        info!(
+            path:% = path.display();
+            "Skipping {:?}: modified {}, not after {}",
+            path,
+            rfc3339(modified),
+            rfc3339(after)
+        );
+        return Some(SkipReason::ModifiedOutOfRange);
+    }
+    if let Some(before) = config.modified_before.map(|bound| bound.resolve(now))
+        && modified >= before
+    {
+        info!(
+            path:% = path.display();
+            "Skipping {:?}: modified {}, not before {}",
+            path,
+            rfc3339(modified),
+            rfc3339(before)
+        );
+        return Some(SkipReason::ModifiedOutOfRange);
+    }
+    None
+}
+
+/// Whether `path`, a file under `current_directory`, is one `collect_files`
+/// would pick up: it is no deeper than `max_depth`, has one of
+/// `file_extensions`, does not sit inside one of `skip_dirs` or
+/// `uploaded_directory`, its name does not look like a temporary file
+/// (`ignore_patterns`), `.dropboxignore` does not exclude it and it passes
+/// `include_globs` and `exclude_globs`.
+pub(crate) fn matches_filters(config: &Config, path: &Path) -> bool {
+    let relative = path.strip_prefix(&config.current_directory).unwrap_or(path);
+    let in_skipped_dir = relative.parent().is_some_and(|dirs| {
+        dirs.components().any(|c| {
+            c.as_os_str().to_str().is_some_and(|c| config.skip_dirs.contains(c))
+        })
+    });
+    let ignored = config
+        .ignore_patterns
+        .as_ref()
+        .zip(path.file_name())
+        .is_some_and(|(patterns, name)| patterns.is_match(name));
+    let too_deep =
+        config.max_depth.is_some_and(|max| relative.components().count() > max);
+    !too_deep
+        && !in_skipped_dir
+        && !in_uploaded_directory(config, path)
+        && !ignored
+        && !is_dropboxignored(config, path, false)
+        && has_wanted_extension(config, path)
+        && config.include_globs.as_ref().is_none_or(|globs| globs.is_match(relative))
+        && !config.exclude_globs.as_ref().is_some_and(|globs| globs.is_match(relative))
+}
+
+fn is_skipped_dir(config: &Config, dir: &Path) -> bool {
+    dir != config.current_directory
+        && (in_uploaded_directory(config, dir)
+            || dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| config.skip_dirs.contains(name))
+            || is_dropboxignored(config, dir, true))
+}
+
+/// Whether `path` is in `uploaded_directory`, which is never scanned even when
+/// it is under `current_directory`: files moved there would be uploaded again.
+fn in_uploaded_directory(config: &Config, path: &Path) -> bool {
+    config.uploaded_directory_in_scan && path.starts_with(&config.uploaded_directory)
+}
+
+fn is_dropboxignored(config: &Config, path: &Path, is_dir: bool) -> bool {
+    config.dropboxignore.as_ref().is_some_and(|rules| {
+        path.starts_with(&config.current_directory)
+            && rules.matched_path_or_any_parents(path, is_dir).is_ignore()
+    })
+}
+
+fn has_wanted_extension(config: &Config, path: &Path) -> bool {
+    let Some(ext) = path.extension().and_then(|s| s.to_str()) else {
+        return false;
+    };
+    if config.case_sensitive_extensions {
+        config.file_extensions.contains(ext)
+    } else {
+        config.file_extensions.contains(&ext.to_lowercase())
+    }
+}
+
+fn rfc3339(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, config_in};
+    use std::time::{Duration, SystemTime};
+
+    /// Creates each of `files` under the source directory and returns what
+    /// `collect_files` finds there, relative to it and sorted.
+    fn collected(config: &Config, files: &[&str]) -> Vec<String> {
+        for file in files {
+            let path = config.current_directory.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x").unwrap();
+        }
+        let mut found = collect_files(config)
+            .unwrap()
+            .iter()
+            .map(|path| {
+                let relative = path.strip_prefix(&config.current_directory).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect::<Vec<_>>();
+        found.sort();
+        found
+    }
+
+    fn write_modified(config: &Config, name: &str, modified: SystemTime) {
+        let path = config.current_directory.join(name);
+        fs::write(&path, "x").unwrap();
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn globs_narrow_the_files_picked_up() {
+        let dir = TempDir::new();
+        let config = config_in(
+            &dir,
+            &[
+                ("RECURSE", "true"),
+                ("INCLUDE_GLOBS", "*.txt, reports/**"),
+                ("EXCLUDE_GLOBS", "**/draft-*"),
+            ],
+        )
+        .unwrap();
+        let files = [
+            "a.txt",
+            "draft-a.txt",
+            "notes/b.txt",
+            "reports/2024/c.txt",
+            "reports/draft-d.txt",
+        ];
+
+        assert_eq!(collected(&config, &files), ["a.txt", "reports/2024/c.txt"]);
+    }
+
+    #[test]
+    fn extensions_and_skipped_dirs_are_honoured() {
+        let dir = TempDir::new();
+        let config = config_in(
+            &dir,
+            &[("RECURSE", "true"), ("FILE_EXTENSIONS", ".TXT,md"), ("SKIP_DIRS", "tmp")],
+        )
+        .unwrap();
+        let files =
+            ["a.txt", "b.MD", "c.log", "tmp/d.txt", "sub/tmp/e.txt", "sub/f.txt"];
+
+        assert_eq!(collected(&config, &files), ["a.txt", "b.MD", "sub/f.txt"]);
+    }
+
+    #[test]
+    fn uploaded_directory_inside_the_scan_is_left_out() {
+        let dir = TempDir::new();
+        let uploaded = dir.path().join("src/done");
+        let config = config_in(
+            &dir,
+            &[("RECURSE", "true"), ("UPLOADED_DIRECTORY", &uploaded.to_string_lossy())],
+        )
+        .unwrap();
+        let files = ["a.txt", "done/b.txt", "done/sub/c.txt", "sub/d.txt"];
+
+        assert_eq!(collected(&config, &files), ["a.txt", "sub/d.txt"]);
+    }
+
+    #[test]
+    fn uploaded_directory_above_the_scan_leaves_it_alone() {
+        let dir = TempDir::new();
+        let config = config_in(
+            &dir,
+            &[
+                ("RECURSE", "true"),
+                ("UPLOADED_DIRECTORY", &dir.path().to_string_lossy()),
+            ],
+        )
+        .unwrap();
+        let files = ["a.txt", "sub/b.txt"];
+
+        assert_eq!(collected(&config, &files), ["a.txt", "sub/b.txt"]);
+    }
+
+    #[test]
+    fn dropboxignore_rules_apply_below_the_scan_directory() {
+        let dir = TempDir::new();
+        let source = dir.path().join("src");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join(".dropboxignore"), "build/\n*.bak.txt\n!keep.bak.txt\n")
+            .unwrap();
+        let config = config_in(&dir, &[("RECURSE", "true")]).unwrap();
+        let files = [
+            "a.txt",
+            "a.bak.txt",
+            "keep.bak.txt",
+            "build/b.txt",
+            "sub/build/c.txt",
+            "sub/d.bak.txt",
+            "sub/e.txt",
+        ];
+
+        assert_eq!(collected(&config, &files), ["a.txt", "keep.bak.txt", "sub/e.txt"]);
+    }
+
+    #[test]
+    fn temporary_files_are_ignored_unless_patterns_are_cleared() {
+        let files = ["a.txt", "~$a.txt", ".#b.txt", "c.txt"];
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[]).unwrap();
+        assert_eq!(collected(&config, &files), ["a.txt", "c.txt"]);
+
+        let config = config_in(&dir, &[("IGNORE_PATTERNS", "")]).unwrap();
+        assert_eq!(collected(&config, &[]), [".#b.txt", "a.txt", "c.txt", "~$a.txt"]);
+    }
+
+    #[test]
+    fn sizes_outside_the_bounds_are_skipped() {
+        let dir = TempDir::new();
+        let config =
+            config_in(&dir, &[("MIN_FILE_SIZE", "2"), ("MAX_FILE_SIZE", "4")]).unwrap();
+        for (name, len) in
+            [("one.txt", 1), ("two.txt", 2), ("four.txt", 4), ("five.txt", 5)]
+        {
+            fs::write(config.current_directory.join(name), vec![b'x'; len]).unwrap();
+        }
+
+        assert_eq!(collected(&config, &[]), ["four.txt", "two.txt"]);
+        assert!(
+            config_in(&dir, &[("MIN_FILE_SIZE", "5"), ("MAX_FILE_SIZE", "4")]).is_err()
+        );
+    }
+
+    #[test]
+    fn modified_times_outside_the_bounds_are_skipped() {
+        let dir = TempDir::new();
+        let config = config_in(
+            &dir,
+            &[("MODIFIED_AFTER", "10d"), ("MODIFIED_BEFORE", "2030-01-01T00:00:00Z")],
+        )
+        .unwrap();
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (name, modified) in [
+            ("old.txt", now - 20 * day),
+            ("recent.txt", now - day),
+            ("future.txt", now + day),
+        ] {
+            write_modified(&config, name, modified);
+        }
+
+        assert_eq!(collected(&config, &[]), ["recent.txt"]);
+    }
+
+    #[test]
+    fn recently_modified_files_are_left_for_later() {
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[("MIN_FILE_AGE", "1h")]).unwrap();
+        let now = SystemTime::now();
+        write_modified(&config, "settled.txt", now - Duration::from_secs(2 * 60 * 60));
+        write_modified(&config, "fresh.txt", now - Duration::from_secs(60));
+
+        assert_eq!(collected(&config, &[]), ["settled.txt"]);
+    }
+}
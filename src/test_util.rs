@@ -0,0 +1,50 @@
+//! Helpers shared by the unit tests.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs, process};
+
+use crate::config::{Config, ConfigBuilder};
+
+/// A directory under the system temp directory, removed again on drop.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path =
+            env::temp_dir().join(format!("fs_library-test-{}-{}", process::id(), n));
+        fs::create_dir_all(&path).expect("create temp dir");
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// A config uploading `.txt` files from `dir/src` to `/Backup` on Dropbox, with
+/// the upload log and sessions kept in `dir` too, and `extra` settings on top.
+pub(crate) fn config_in(dir: &TempDir, extra: &[(&str, &str)]) -> Result<Config> {
+    let source = dir.path().join("src");
+    fs::create_dir_all(&source)?;
+    let builder = ConfigBuilder::new()
+        .set("API_ADDRESS", "http://127.0.0.1:9/2/files/upload")
+        .set("API_REFRESH_ADDRESS", "http://127.0.0.1:9/oauth2/token")
+        .current_directory(&source)
+        .uploaded_directory(dir.path().join("uploaded"))
+        .uploaded_files_log(dir.path().join("uploaded.log"))
+        .file_extensions(["txt"])
+        .dropbox_dir("/Backup")
+        .credentials("key", "secret", "refresh")
+        .set("SHORT_TOKEN_FILE", dir.path().join("short_token").display());
+    extra.iter().fold(builder, |builder, (key, value)| builder.set(key, value)).build()
+}
@@ -0,0 +1,122 @@
+//! Capping the upload bandwidth.
+
+use chrono::{Local, Timelike};
+use futures::stream::{Stream, StreamExt};
+use std::cmp::Ordering;
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Size of the pieces request bodies are metered in.
+pub(crate) const PIECE_SIZE: usize = 64 * 1024;
+
+/// A token bucket shared by every upload, so the limit applies to their total.
+/// Clones share the bucket.
+///
+/// The bucket holds up to a second's worth of bytes (at least one piece), so
+/// short bursts go out at full speed while the average stays at the limit.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// Local hours `[start, end)` during which the limit applies; it wraps past
+    /// midnight when `start > end`, and equal bounds mean all day. `None` means
+    /// always.
+    hours: Option<Range<u32>>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Can go negative: a caller that overdraws it sleeps off the debt.
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64, hours: Option<Range<u32>>) -> Self {
+        Self {
+            bytes_per_sec,
+            hours,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// Whether the limit applies during local hour `hour`.
+    fn active_at(&self, hour: u32) -> bool {
+        let Some(hours) = &self.hours else {
+            return true;
+        };
+        match hours.start.cmp(&hours.end) {
+            Ordering::Less => hours.contains(&hour),
+            Ordering::Equal => true,
+            Ordering::Greater => hour >= hours.start || hour < hours.end,
+        }
+    }
+
+    /// Waits until `bytes` may be sent.
+    pub async fn acquire(&self, bytes: usize) {
+        if !self.active_at(Local::now().hour()) {
+            return;
+        }
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+            let now = Instant::now();
+            let capacity = rate.max(PIECE_SIZE as f64);
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+            (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Passes `body` through `limiter`, if any.
+pub(crate) fn throttled<S, B>(
+    body: S,
+    limiter: Option<RateLimiter>,
+) -> impl Stream<Item = io::Result<B>> + Send + 'static
+where
+    S: Stream<Item = io::Result<B>> + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+{
+    body.then(move |piece| {
+        let limiter = limiter.clone();
+        async move {
+            if let (Some(limiter), Ok(piece)) = (&limiter, &piece) {
+                limiter.acquire(piece.as_ref().len()).await;
+            }
+            piece
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active_hours(hours: Option<Range<u32>>) -> Vec<u32> {
+        let limiter = RateLimiter::new(1, hours);
+        (0..24).filter(|&hour| limiter.active_at(hour)).collect()
+    }
+
+    #[test]
+    fn hours_wrap_past_midnight() {
+        assert_eq!(active_hours(Some(8..20)), (8..20).collect::<Vec<_>>());
+        assert_eq!(
+            active_hours(Some(Range { start: 22, end: 6 })),
+            [0, 1, 2, 3, 4, 5, 22, 23]
+        );
+        assert_eq!(active_hours(Some(0..0)), (0..24).collect::<Vec<_>>());
+        assert_eq!(active_hours(None), (0..24).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,1294 @@
+//! Uploading a single file: hashing, deduplication against the upload log,
+//! the transfer itself through a [`Backend`], then logging and moving it away.
+//! The Dropbox transfer goes out in one request or through an upload session.
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::fs as tokio_fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::auth::TokenCache;
+use crate::backend::{Backend, Payload};
+use crate::config::{Config, LogPaths, OnConflict, PostUploadAction, UploadMode};
+use crate::dropbox::{DropboxClient, FileMetadata};
+use crate::files::{
+    content_hash_async, extract_filename, move_file, not_utf8, quick_hash_async,
+    sanitize_filename_spaces, sanitized_path,
+};
+use crate::hook;
+use crate::http::{UploadError, http_status_error, is_conflict, with_retries};
+use crate::ledger::{LogEntry, UploadLog};
+use crate::prepare::{prepare, remote_suffix};
+use crate::progress::Bar;
+use crate::scan::SkipReason;
+
+/// Destination of `local_file` under `dropbox_dir`, or the folder `routes` maps
+/// its source directory to, and there in the subfolder `ext_routes` maps its
+/// extension to: just its file name with spaces sanitized, or with
+/// `preserve_tree` its path relative to the source directory (by default
+/// `current_directory`) using `/` separators. A `dropbox_path_template` is
+/// rendered around that name with the file's modified date.
+pub(crate) fn dropbox_path(config: &Config, local_file: &Path) -> Result<String> {
+    let renamed = sanitized_path(local_file)?;
+    let (root, dropbox_dir) =
+        match config.routes.iter().find(|(source, _)| renamed.starts_with(source)) {
+            Some((source, dir)) => (source, dir),
+            None => (&config.current_directory, &config.dropbox_dir),
+        };
+    let mut relative = match renamed.strip_prefix(root) {
+        Ok(relative) if config.preserve_tree => relative
+            .components()
+            .map(|c| c.as_os_str().to_str().ok_or_else(not_utf8))
+            .collect::<Result<Vec<_>>>()?
+            .join("/"),
+        _ => extract_filename(&renamed)?,
+    };
+    if let Some(template) = &config.dropbox_path_template {
+        let modified = fs::metadata(local_file)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("Read modified time of {:?}", local_file))?;
+        relative = template.render(modified.into(), &relative);
+    }
+    let route = local_file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| config.ext_routes.get(&ext.to_lowercase()))
+        .map(|folder| format!("{}/", folder))
+        .unwrap_or_default();
+    Ok(format!(
+        "{}/{}{}{}",
+        dropbox_dir.trim_end_matches('/'),
+        route,
+        relative,
+        remote_suffix(config)
+    ))
+}
+
+/// Builds the `CommitInfo` argument for `payload`. In `update` mode the
+/// current remote revision is looked up first; a file that does not exist yet
+/// on Dropbox is simply added. With `set_client_modified` the file's local
+/// modified time is sent along so Dropbox shows it instead of the upload time.
+async fn commit_arg(
+    client: &impl DropboxClient,
+    config: &Config,
+    payload: &Payload<'_>,
+    short_token: &str,
+) -> Result<serde_json::Value> {
+    let path_arg = payload.remote_path.as_str();
+    let mode = match config.upload_mode {
+        UploadMode::Add => serde_json::json!("add"),
+        UploadMode::Overwrite => serde_json::json!("overwrite"),
+        UploadMode::Update => {
+            match client.get_metadata(config, short_token, path_arg).await? {
+                Some(FileMetadata { rev: Some(rev), .. }) => {
+                    serde_json::json!({ ".tag": "update", "update": rev })
+                }
+                _ => serde_json::json!("add"),
+            }
+        }
+    };
+    let mut commit = serde_json::json!({
+        "autorename": config.autorename,
+        "mode": mode,
+        "mute": config.mute,
+        "path": path_arg,
+        "strict_conflict": config.strict_conflict,
+    });
+    if config.set_client_modified {
+        let modified: DateTime<Utc> = tokio_fs::metadata(payload.local_file)
+            .await?
+            .modified()
+            .with_context(|| format!("Read modified time of {:?}", payload.local_file))?
+            .into();
+        // Dropbox rejects fractional seconds here.
+        commit["client_modified"] =
+            serde_json::json!(modified.to_rfc3339_opts(SecondsFormat::Secs, true));
+    }
+    Ok(commit)
+}
+
+/// One attempt at uploading `payload`, classified for [`with_retries`].
+async fn upload_file_once(
+    client: &impl DropboxClient,
+    config: &Config,
+    payload: &Payload<'_>,
+    short_token: &str,
+) -> Result<FileMetadata, UploadError> {
+    Ok(upload_attempt(client, config, payload, short_token).await?)
+}
+
+async fn upload_attempt(
+    client: &impl DropboxClient,
+    config: &Config,
+    payload: &Payload<'_>,
+    short_token: &str,
+) -> Result<FileMetadata> {
+    let size = tokio_fs::metadata(payload.data).await?.len();
+    let dropbox_arg = commit_arg(client, config, payload, short_token).await?;
+    if size > config.chunked_upload_threshold {
+        return upload_file_chunked(
+            client,
+            config,
+            payload,
+            short_token,
+            size,
+            dropbox_arg,
+        )
+        .await;
+    }
+
+    client.upload(config, short_token, &dropbox_arg, payload.data, size).await
+}
+
+/// Progress of an in-flight upload session, checkpointed after every chunk.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadSessionState {
+    session_id: String,
+    offset: u64,
+    /// Of the bytes being sent. Encrypting again gives different ones, which
+    /// cannot continue a session started with the old.
+    #[serde(default)]
+    content_hash: String,
+}
+
+fn load_session_state(path: &Path) -> Result<Option<UploadSessionState>> {
+    match fs::read_to_string(path) {
+        Ok(text) => match serde_json::from_str(&text) {
+            Ok(state) => Ok(Some(state)),
+            Err(e) => {
+                warn!("Ignoring unreadable upload session file {:?}: {}", path, e);
+                Ok(None)
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Read upload session file {:?}", path)),
+    }
+}
+
+fn save_session_state(path: &Path, state: &UploadSessionState) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, serde_json::to_vec(state)?)?;
+    fs::rename(&temp, path)
+        .with_context(|| format!("Write upload session file {:?}", path))
+}
+
+/// What the files in `upload_session_dir` for uploading a file with
+/// `local_hash` to `remote_path` are named, so two files with the same content
+/// going to different destinations at once do not share them.
+fn upload_state_name(local_hash: &str, remote_path: &str) -> String {
+    let path_hash = format!("{:x}", Sha256::digest(remote_path.as_bytes()));
+    format!("{}-{}", local_hash, &path_hash[..16])
+}
+
+/// Written before a file is sent and removed once it is in the upload log, so a
+/// run killed in between can tell that the upload may already have gone
+/// through.
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadIntent {
+    remote_path: String,
+    /// Of the bytes sent, which differ from the local file's when compressed or
+    /// encrypted.
+    content_hash: String,
+}
+
+fn intent_path(config: &Config, local_hash: &str, remote_path: &str) -> PathBuf {
+    config
+        .upload_session_dir
+        .join(format!("{}.intent", upload_state_name(local_hash, remote_path)))
+}
+
+/// Writes `intent` to `path` and syncs it, so it is on disk before the upload
+/// it announces starts.
+fn save_intent(path: &Path, intent: &UploadIntent) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension("intent.tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(&serde_json::to_vec(intent)?)?;
+    file.sync_data()?;
+    fs::rename(&temp, path).with_context(|| format!("Write upload intent {:?}", path))
+}
+
+fn load_intent(path: &Path) -> Result<Option<UploadIntent>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(serde_json::from_str(&text).ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Read upload intent {:?}", path)),
+    }
+}
+
+/// What an interrupted run left at the remote path of the intent at `path`, if it
+/// is exactly what that run was sending.
+async fn interrupted_upload(
+    backend: &impl Backend,
+    config: &Config,
+    path: &Path,
+) -> Result<Option<(String, FileMetadata)>> {
+    let Some(intent) = load_intent(path)? else {
+        return Ok(None);
+    };
+    let metadata = backend.metadata(config, &intent.remote_path).await?;
+    Ok(metadata
+        .filter(|m| m.content_hash.as_deref() == Some(intent.content_hash.as_str()))
+        .map(|m| (intent.remote_path, m)))
+}
+
+/// Whether Dropbox rejected a saved session because it is gone, closed or at a
+/// different offset: a 409 whose error is one of those, reported under
+/// `lookup_failed` by `finish` and directly by `append_v2`. Anything else,
+/// such as a 401 for an expired token, leaves the session to resume later.
+fn is_stale_session(err: &anyhow::Error) -> bool {
+    const STALE: [&str; 3] = ["not_found", "incorrect_offset", "closed"];
+    http_status_error(err).is_some_and(|e| {
+        e.status == StatusCode::CONFLICT
+            && e.api_error.as_ref().is_some_and(|api_error| {
+                let tag = match api_error.tag() {
+                    Some("lookup_failed") => api_error.inner_tag("lookup_failed"),
+                    tag => tag,
+                };
+                tag.is_some_and(|tag| STALE.contains(&tag))
+            })
+    })
+}
+
+/// Uploads `payload` through an upload session: `start`, one `append_v2` per
+/// chunk of `upload_chunk_size` bytes, then `finish` with the commit info.
+///
+/// The session id and committed offset are kept in a sidecar file named after
+/// the local file's content hash and the destination, so an interrupted
+/// transfer picks up where it left off on the next attempt or run instead of
+/// starting from byte zero. A session for different bytes, as an encrypted
+/// upload's are each time, is started over, replacing the sidecar.
+async fn upload_file_chunked(
+    client: &impl DropboxClient,
+    config: &Config,
+    payload: &Payload<'_>,
+    short_token: &str,
+    size: u64,
+    commit: serde_json::Value,
+) -> Result<FileMetadata> {
+    let local_file = payload.local_file;
+    let name = upload_state_name(payload.local_hash, &payload.remote_path);
+    let state_path = config.upload_session_dir.join(format!("{}.json", name));
+    let saved = load_session_state(&state_path)?;
+    if let Some(state) = &saved
+        && state.content_hash != payload.content_hash
+    {
+        info!(
+            path:% = local_file.display();
+            "Not resuming the upload session for {:?}: the bytes to send have changed",
+            local_file
+        );
+    }
+    if let Some(state) =
+        saved.filter(|s| s.content_hash == payload.content_hash && s.offset <= size)
+    {
+        info!(
+            path:% = local_file.display();
+            "Resuming upload session for {:?} at {}/{} bytes",
+            local_file, state.offset, size
+        );
+        let result = continue_upload_session(
+            client,
+            config,
+            payload,
+            short_token,
+            &commit,
+            state,
+            &state_path,
+        )
+        .await;
+        match result {
+            Err(e) if is_stale_session(&e) => {
+                warn!(
+                    path:% = local_file.display();
+                    "Saved upload session for {:?} is no longer valid: {}",
+                    local_file, e
+                );
+            }
+            result => return result,
+        }
+    }
+
+    info!(path:% = local_file.display(); "Starting upload session for {:?} ({} bytes)", local_file, size);
+    let session_id = client.start_upload_session(config, short_token).await?;
+    let state = UploadSessionState {
+        session_id,
+        offset: 0,
+        content_hash: payload.content_hash.to_string(),
+    };
+    save_session_state(&state_path, &state)?;
+
+    continue_upload_session(
+        client,
+        config,
+        payload,
+        short_token,
+        &commit,
+        state,
+        &state_path,
+    )
+    .await
+}
+
+/// Appends the rest of `payload.data` from `state.offset` onwards and finishes
+/// the session, removing the sidecar file once Dropbox has committed it.
+///
+/// Each chunk is retried on its own, up to `chunk_retry` allows, before the
+/// failure counts against the file as a whole.
+async fn continue_upload_session(
+    client: &impl DropboxClient,
+    config: &Config,
+    payload: &Payload<'_>,
+    short_token: &str,
+    commit: &serde_json::Value,
+    mut state: UploadSessionState,
+    state_path: &Path,
+) -> Result<FileMetadata> {
+    let local_file = payload.local_file;
+    let mut file = tokio_fs::File::open(payload.data).await?;
+    let size = file.metadata().await?.len();
+    file.seek(io::SeekFrom::Start(state.offset)).await?;
+    let name = local_file.file_name().unwrap_or_default().to_string_lossy();
+    let bar = Bar::bytes(name.into_owned(), size, state.offset);
+    loop {
+        let mut chunk = Vec::with_capacity(config.upload_chunk_size as usize);
+        (&mut file).take(config.upload_chunk_size).read_to_end(&mut chunk).await?;
+        if chunk.is_empty() {
+            break;
+        }
+        let len = chunk.len() as u64;
+        // Against the same session and offset, so only this chunk is resent.
+        with_retries(&config.chunk_retry, "Append chunk", || {
+            client.append_upload_session(
+                config,
+                short_token,
+                &state.session_id,
+                state.offset,
+                chunk.clone(),
+            )
+        })
+        .await?;
+        state.offset += len;
+        save_session_state(state_path, &state)?;
+        bar.set_position(state.offset);
+        info!(path:% = local_file.display(); "Uploaded {}/{} bytes of {:?}", state.offset, size, local_file);
+    }
+
+    let metadata = client
+        .finish_upload_session(
+            config,
+            short_token,
+            &state.session_id,
+            state.offset,
+            commit,
+        )
+        .await?;
+    if let Err(e) = fs::remove_file(state_path) {
+        warn!("Could not remove upload session file {:?}: {}", state_path, e);
+    }
+    Ok(metadata)
+}
+
+/// [`Backend::upload`] for Dropbox: uploads with retries, and once more with a
+/// fresh token if the current one is rejected.
+pub(crate) async fn upload_to_dropbox(
+    client: &impl DropboxClient,
+    config: &Config,
+    tokens: &TokenCache,
+    payload: &Payload<'_>,
+) -> Result<FileMetadata> {
+    let token = tokens.get(client, config).await?;
+    match upload_with_retries(client, config, payload, &token).await {
+        Err(UploadError::Unauthorized(_)) => {
+            warn!("Token expired/unauthorized. Refreshing...");
+            let token = tokens.refresh(client, config, &token).await?;
+            Ok(upload_with_retries(client, config, payload, &token).await?)
+        }
+        result => Ok(result?),
+    }
+}
+
+async fn upload_with_retries(
+    client: &impl DropboxClient,
+    config: &Config,
+    payload: &Payload<'_>,
+    short_token: &str,
+) -> Result<FileMetadata, UploadError> {
+    with_retries(&config.retry, "Upload", || {
+        upload_file_once(client, config, payload, short_token)
+    })
+    .await
+}
+
+/// What [`upload_file`] did with a file.
+#[derive(Debug)]
+pub enum UploadOutcome {
+    /// Sent `bytes` of the local file, before compression or encryption, to
+    /// `remote_path`. `content_hash` is that of the local file, as logged.
+    Uploaded { bytes: u64, remote_path: String, content_hash: String },
+    /// Nothing needed uploading, for the reason given: the upload log already
+    /// had the file's content hash, Dropbox had it at the destination, or
+    /// something else is there and `on_conflict` is `skip`.
+    Skipped(SkipReason),
+    /// This file could not be uploaded, logged or moved; other files may still
+    /// succeed. Converting the error to an [`UploadError`] tells whether trying
+    /// it again later is worthwhile.
+    Failed(anyhow::Error),
+}
+
+/// Context marking an error as the upload log failing, which every other file
+/// would run into too, so [`upload_file`] returns it as `Err` rather than as
+/// [`UploadOutcome::Failed`].
+#[derive(Debug)]
+struct UploadLogFailed;
+
+impl std::fmt::Display for UploadLogFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Upload log failed")
+    }
+}
+
+/// Uploads `local_file` unless the upload log already has it, then logs it and
+/// moves it to `uploaded_directory`, deletes it or leaves it, as
+/// `post_upload_action` says. With compression or encryption enabled the
+/// transformed bytes are uploaded instead, under the Dropbox path plus `.gz`
+/// and/or `.enc`.
+///
+/// The file is only moved or deleted once its log entry is on disk. An upload intent
+/// recorded before the transfer covers a crash between Dropbox accepting the
+/// file and the log entry: if the next run finds the announced bytes at the
+/// announced path, it logs them instead of uploading a duplicate.
+///
+/// Anything going wrong with this file is [`UploadOutcome::Failed`]; an `Err`
+/// means the upload log itself could not be read or written, and no further
+/// files should be tried.
+pub async fn upload_file(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+    local_file: &Path,
+) -> Result<UploadOutcome> {
+    match process_file(backend, config, upload_log, local_file).await {
+        Err(e) if e.downcast_ref::<UploadLogFailed>().is_none() => {
+            Ok(UploadOutcome::Failed(e))
+        }
+        result => result,
+    }
+}
+
+async fn process_file(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+    local_file: &Path,
+) -> Result<UploadOutcome> {
+    let quick_hash = match config.quick_hash {
+        true => Some(quick_hash_async(local_file).await?),
+        false => None,
+    };
+    let local_hash = match look_up(upload_log, local_file, quick_hash.as_deref()).await?
+    {
+        Lookup::Logged => {
+            info!(path:% = local_file.display(); "Already uploaded, skipping: {:?}", local_file);
+            return Ok(UploadOutcome::Skipped(SkipReason::AlreadyUploaded));
+        }
+        Lookup::New(Some(local_hash)) => local_hash,
+        // Still needed to name the intent and session and to log the upload.
+        Lookup::New(None) => content_hash_async(local_file).await?,
+    };
+    // Checked before renaming, so a name that cannot be uploaded stays as it is.
+    let remote_path = dropbox_path(config, local_file)?;
+    // Only files that are actually going to be uploaded get renamed.
+    let local_file = &sanitize_filename_spaces(local_file)?;
+
+    let intent = intent_path(config, &local_hash, &remote_path);
+    let size = tokio_fs::metadata(local_file).await?.len();
+    let (remote_path, metadata, uploaded) = match interrupted_upload(
+        backend, config, &intent,
+    )
+    .await?
+    {
+        Some((remote_path, metadata)) => {
+            info!(
+                path:% = local_file.display();
+                "{:?} was already uploaded to {} by an interrupted run, logging it",
+                local_file, remote_path
+            );
+            (remote_path, metadata, true)
+        }
+        None if let Some(metadata) =
+            remote_match(backend, config, &remote_path, &local_hash).await? =>
+        {
+            info!(
+                path:% = local_file.display();
+                "{:?} is unchanged at {}, not uploading it again",
+                local_file, remote_path
+            );
+            (remote_path, metadata, false)
+        }
+        None => {
+            let started = Instant::now();
+            let transferred = transfer(
+                backend,
+                config,
+                local_file,
+                &local_hash,
+                &remote_path,
+                Some(&intent),
+            )
+            .await;
+            let metadata = match transferred {
+                Err(e) if config.on_conflict == OnConflict::Skip && is_conflict(&e) => {
+                    warn!(
+                        path:% = local_file.display();
+                        "Not uploading {:?}: {} already exists", local_file, remote_path
+                    );
+                    remove_intent(&intent);
+                    return Ok(UploadOutcome::Skipped(SkipReason::Conflict));
+                }
+                result => result?,
+            };
+            let elapsed = started.elapsed();
+            // Over the whole transfer, so across all chunks of a session.
+            let secs = elapsed.as_secs_f64();
+            let mb_per_sec = if secs > 0.0 { size as f64 / secs / 1e6 } else { 0.0 };
+            info!(
+                path:% = local_file.display(),
+                remote_path:% = remote_path,
+                size = size,
+                duration_ms = elapsed.as_millis() as u64,
+                mb_per_sec = mb_per_sec;
+                "Uploaded {:?} -> {} ({} bytes in {:.1?}, {:.2} MB/s)",
+                local_file, remote_path, size, elapsed, mb_per_sec
+            );
+            (remote_path, metadata, true)
+        }
+    };
+    let logged_path = match config.log_paths {
+        LogPaths::Absolute => local_file,
+        LogPaths::Relative => {
+            local_file.strip_prefix(&config.current_directory).unwrap_or(local_file)
+        }
+    };
+    upload_log
+        .record(&LogEntry {
+            path: logged_path,
+            content_hash: &local_hash,
+            size,
+            rev: metadata.rev.as_deref(),
+            remote_path: &remote_path,
+            quick_hash: quick_hash.as_deref(),
+        })
+        .context(UploadLogFailed)?;
+    remove_intent(&intent);
+    let local_path = match config.post_upload_action {
+        PostUploadAction::Move => move_file(
+            local_file,
+            &config.uploaded_directory,
+            config.move_collision,
+            config.verify_move.then_some(local_hash.as_str()),
+        )?,
+        PostUploadAction::Delete => {
+            fs::remove_file(local_file)
+                .with_context(|| format!("Delete uploaded file {:?}", local_file))?;
+            info!(path:% = local_file.display(); "Deleted {:?}", local_file);
+            local_file.to_path_buf()
+        }
+        PostUploadAction::Keep => local_file.to_path_buf(),
+    };
+    if uploaded {
+        hook::post_upload(config, local_file, &local_path, &remote_path).await;
+    }
+    Ok(if uploaded {
+        UploadOutcome::Uploaded { bytes: size, remote_path, content_hash: local_hash }
+    } else {
+        UploadOutcome::Skipped(SkipReason::RemoteMatch)
+    })
+}
+
+fn remove_intent(intent: &Path) {
+    if let Err(e) = fs::remove_file(intent)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        warn!("Could not remove upload intent {:?}: {}", intent, e);
+    }
+}
+
+/// What the upload log says about a file, by [`look_up`].
+#[derive(Debug, PartialEq)]
+enum Lookup {
+    Logged,
+    /// Not uploaded yet, or changed since, with its content hash if that had to
+    /// be computed to tell.
+    New(Option<String>),
+}
+
+/// Looks `local_file` up in `upload_log`, by `quick_hash` if given. A file
+/// whose quick hash is not logged is new and is not read past the two samples;
+/// only a logged one is hashed in full to confirm the match.
+async fn look_up(
+    upload_log: &UploadLog,
+    local_file: &Path,
+    quick_hash: Option<&str>,
+) -> Result<Lookup> {
+    let Some(quick_hash) = quick_hash else {
+        let local_hash = content_hash_async(local_file).await?;
+        return Ok(
+            match upload_log
+                .contains(local_file, &local_hash)
+                .context(UploadLogFailed)?
+            {
+                true => Lookup::Logged,
+                false => Lookup::New(Some(local_hash)),
+            },
+        );
+    };
+    let Some(logged) =
+        upload_log.quick_hash_match(quick_hash).context(UploadLogFailed)?
+    else {
+        info!(
+            path:% = local_file.display();
+            "{:?} is not in the upload log by its quick hash, uploading it as new",
+            local_file
+        );
+        return Ok(Lookup::New(None));
+    };
+    let local_hash = content_hash_async(local_file).await?;
+    if logged == local_hash {
+        return Ok(Lookup::Logged);
+    }
+    warn!(
+        path:% = local_file.display();
+        "{:?} changed without its size, modified time or ends changing; uploading it again",
+        local_file
+    );
+    Ok(Lookup::New(Some(local_hash)))
+}
+
+/// With `skip_if_remote_matches`, the metadata at `remote_path` if the file
+/// there has `local_hash`. Compressed and encrypted uploads are never compared:
+/// their bytes differ from the local file's, and encryption's every time.
+async fn remote_match(
+    backend: &impl Backend,
+    config: &Config,
+    remote_path: &str,
+    local_hash: &str,
+) -> Result<Option<FileMetadata>> {
+    if !config.skip_if_remote_matches || !remote_suffix(config).is_empty() {
+        return Ok(None);
+    }
+    let metadata = backend.metadata(config, remote_path).await?;
+    Ok(metadata.filter(|m| m.content_hash.as_deref() == Some(local_hash)))
+}
+
+/// Sends `local_file`, compressed and encrypted as configured, to `remote_path`
+/// and checks what arrived. With `intent`, an [`UploadIntent`] is saved there
+/// first.
+pub(crate) async fn transfer(
+    backend: &impl Backend,
+    config: &Config,
+    local_file: &Path,
+    local_hash: &str,
+    remote_path: &str,
+    intent: Option<&Path>,
+) -> Result<FileMetadata> {
+    let name = upload_state_name(local_hash, remote_path);
+    let prepared = prepare(config, local_file, &name).await?;
+    let payload = Payload {
+        local_file,
+        data: prepared.as_ref().map_or(local_file, |p| &p.path),
+        content_hash: prepared.as_ref().map_or(local_hash, |p| &p.content_hash),
+        local_hash,
+        remote_path: remote_path.to_string(),
+    };
+    if let Some(intent) = intent {
+        save_intent(
+            intent,
+            &UploadIntent {
+                remote_path: payload.remote_path.clone(),
+                content_hash: payload.content_hash.to_string(),
+            },
+        )?;
+    }
+    let metadata = match backend.upload(config, &payload).await {
+        Err(e) if is_conflict(&e) => {
+            upload_around_conflict(backend, config, &payload, e).await?
+        }
+        result => result?,
+    };
+    if config.verify_content_hash {
+        verify_content_hash(local_file, payload.content_hash, &metadata)?;
+    }
+    Ok(metadata)
+}
+
+/// Retries an upload whose destination turned out to be taken with
+/// `autorename` or in `overwrite` mode, as `on_conflict` says, or returns `err`.
+async fn upload_around_conflict(
+    backend: &impl Backend,
+    config: &Config,
+    payload: &Payload<'_>,
+    err: anyhow::Error,
+) -> Result<FileMetadata> {
+    let config = match config.on_conflict {
+        OnConflict::Fail | OnConflict::Skip => return Err(err),
+        OnConflict::Rename => {
+            info!(
+                path:% = payload.local_file.display();
+                "{} already exists, uploading under a new name", payload.remote_path
+            );
+            Config { autorename: true, ..config.clone() }
+        }
+        OnConflict::Overwrite => {
+            info!(
+                path:% = payload.local_file.display();
+                "{} already exists, replacing it", payload.remote_path
+            );
+            Config { upload_mode: UploadMode::Overwrite, ..config.clone() }
+        }
+    };
+    backend.upload(&config, payload).await
+}
+
+fn verify_content_hash(
+    local_file: &Path,
+    local_hash: &str,
+    metadata: &FileMetadata,
+) -> Result<(), UploadError> {
+    match metadata.content_hash.as_deref() {
+        Some(remote) if remote == local_hash => Ok(()),
+        Some(remote) => Err(UploadError::Integrity(anyhow!(
+            "Content hash mismatch for {:?}: local {} != remote {}",
+            local_file,
+            local_hash,
+            remote
+        ))),
+        None => Err(UploadError::Integrity(anyhow!(
+            "Dropbox returned no content_hash for {:?}",
+            local_file
+        ))),
+    }
+}
+
+/// Reports what [`upload_file`] would do with `local_file` without touching the
+/// filesystem or calling the Dropbox API.
+pub(crate) async fn dry_run_file(
+    config: &Config,
+    upload_log: &UploadLog,
+    local_file: &Path,
+) -> Result<()> {
+    let quick_hash = match config.quick_hash {
+        true => Some(quick_hash_async(local_file).await?),
+        false => None,
+    };
+    if look_up(upload_log, local_file, quick_hash.as_deref()).await? == Lookup::Logged {
+        info!(path:% = local_file.display(); "[dry-run] Already uploaded, would skip: {:?}", local_file);
+        return Ok(());
+    }
+    let renamed = sanitized_path(local_file)?;
+    if renamed != local_file {
+        info!(path:% = local_file.display(); "[dry-run] Would rename file: {:?} -> {:?}", local_file, renamed);
+    }
+    let then = match config.post_upload_action {
+        PostUploadAction::Move => format!("move it to {:?}", config.uploaded_directory),
+        PostUploadAction::Delete => "delete it".to_string(),
+        PostUploadAction::Keep => "keep it".to_string(),
+    };
+    info!(
+        path:% = local_file.display();
+        "[dry-run] Would upload {:?} -> {} and {}",
+        local_file,
+        dropbox_path(config, local_file)?,
+        then
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ShortToken;
+    use crate::dropbox::{Account, SaveUrlJob, SaveUrlStatus};
+    use crate::files::content_hash;
+    use crate::files::quick_hash;
+    use crate::http::{DropboxApiError, HttpStatusError};
+    use crate::ledger::FileLog;
+    use crate::test_util::{TempDir, config_in};
+    use serde_json::json;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    const MIB: usize = 1 << 20;
+
+    /// What [`MockClient`] was asked to do, with chunks reduced to where they
+    /// start and how long they are.
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        GetMetadata(String),
+        Upload { commit: serde_json::Value, size: u64 },
+        Start,
+        Append { session_id: String, offset: u64, len: usize },
+        Finish { session_id: String, offset: u64, commit: serde_json::Value },
+    }
+
+    /// Records every call and accepts whatever is sent, except that appends to
+    /// the `saved` session fail with `saved_rejected` if set. `rev` is reported
+    /// for any path asked about.
+    #[derive(Default)]
+    struct MockClient {
+        calls: Mutex<Vec<Call>>,
+        rev: Option<String>,
+        saved_rejected: Option<(StatusCode, serde_json::Value)>,
+    }
+
+    impl MockClient {
+        fn record(&self, call: Call) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn calls(self) -> Vec<Call> {
+            self.calls.into_inner().unwrap()
+        }
+    }
+
+    fn uploaded() -> FileMetadata {
+        FileMetadata { content_hash: None, rev: Some("rev1".to_string()) }
+    }
+
+    impl DropboxClient for MockClient {
+        async fn refresh_token(&self, _: &Config) -> Result<ShortToken> {
+            unreachable!("the tests pass a token")
+        }
+
+        async fn get_current_account(&self, _: &Config, _: &str) -> Result<Account> {
+            unreachable!("not part of an upload")
+        }
+
+        async fn get_metadata(
+            &self,
+            _: &Config,
+            _: &str,
+            path: &str,
+        ) -> Result<Option<FileMetadata>> {
+            self.record(Call::GetMetadata(path.to_string()));
+            let rev = self.rev.clone();
+            Ok(rev.map(|rev| FileMetadata { content_hash: None, rev: Some(rev) }))
+        }
+
+        async fn upload(
+            &self,
+            _: &Config,
+            _: &str,
+            commit: &serde_json::Value,
+            _: &Path,
+            size: u64,
+        ) -> Result<FileMetadata> {
+            self.record(Call::Upload { commit: commit.clone(), size });
+            Ok(uploaded())
+        }
+
+        async fn start_upload_session(&self, _: &Config, _: &str) -> Result<String> {
+            self.record(Call::Start);
+            Ok("new".to_string())
+        }
+
+        async fn append_upload_session(
+            &self,
+            _: &Config,
+            _: &str,
+            session_id: &str,
+            offset: u64,
+            chunk: Vec<u8>,
+        ) -> Result<()> {
+            self.record(Call::Append {
+                session_id: session_id.to_string(),
+                offset,
+                len: chunk.len(),
+            });
+            match &self.saved_rejected {
+                Some((status, error)) if session_id == "saved" => Err(HttpStatusError {
+                    what: "Append chunk".to_string(),
+                    status: *status,
+                    body: error.to_string(),
+                    api_error: Some(DropboxApiError {
+                        error_summary: String::new(),
+                        error: error.clone(),
+                    }),
+                    retry_after: None,
+                }
+                .into()),
+                _ => Ok(()),
+            }
+        }
+
+        async fn finish_upload_session(
+            &self,
+            _: &Config,
+            _: &str,
+            session_id: &str,
+            offset: u64,
+            commit: &serde_json::Value,
+        ) -> Result<FileMetadata> {
+            let (session_id, commit) = (session_id.to_string(), commit.clone());
+            self.record(Call::Finish { session_id, offset, commit });
+            Ok(uploaded())
+        }
+
+        async fn save_url(
+            &self,
+            _: &Config,
+            _: &str,
+            _: &str,
+            _: &str,
+        ) -> Result<SaveUrlJob> {
+            unreachable!("not part of an upload")
+        }
+
+        async fn check_save_url_job(
+            &self,
+            _: &Config,
+            _: &str,
+            _: &str,
+        ) -> Result<SaveUrlStatus> {
+            unreachable!("not part of an upload")
+        }
+    }
+
+    /// Writes `len` bytes to `name` in the source directory and returns its
+    /// path and content hash.
+    fn source_file(config: &Config, name: &str, len: usize) -> (PathBuf, String) {
+        let local_file = config.current_directory.join(name);
+        fs::write(&local_file, vec![b'x'; len]).unwrap();
+        let local_hash = content_hash(&local_file).unwrap();
+        (local_file, local_hash)
+    }
+
+    async fn try_upload(
+        client: &MockClient,
+        config: &Config,
+        name: &str,
+        len: usize,
+    ) -> Result<FileMetadata, UploadError> {
+        let (local_file, local_hash) = source_file(config, name, len);
+        let payload = Payload {
+            local_file: &local_file,
+            data: &local_file,
+            content_hash: &local_hash,
+            local_hash: &local_hash,
+            remote_path: dropbox_path(config, &local_file).unwrap(),
+        };
+        upload_file_once(client, config, &payload, "token").await
+    }
+
+    async fn upload(client: &MockClient, config: &Config, name: &str, len: usize) {
+        try_upload(client, config, name, len).await.unwrap();
+    }
+
+    fn chunked(dir: &TempDir) -> Config {
+        let four_mib = (4 * MIB).to_string();
+        config_in(
+            dir,
+            &[
+                ("CHUNKED_UPLOAD_THRESHOLD", &four_mib),
+                ("UPLOAD_CHUNK_SIZE", &four_mib),
+                ("SET_CLIENT_MODIFIED", "false"),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn commit(path: &str) -> serde_json::Value {
+        json!({
+            "autorename": false,
+            "mode": "add",
+            "mute": false,
+            "path": path,
+            "strict_conflict": false,
+        })
+    }
+
+    fn append(session_id: &str, offset: usize, len: usize) -> Call {
+        Call::Append { session_id: session_id.to_string(), offset: offset as u64, len }
+    }
+
+    #[tokio::test]
+    async fn small_file_is_sent_in_one_request() {
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[]).unwrap();
+        let client = MockClient::default();
+        upload(&client, &config, "a.txt", 10).await;
+
+        let calls = client.calls();
+        let [Call::Upload { commit: sent, size: 10 }] = calls.as_slice() else {
+            panic!("unexpected calls: {:?}", calls);
+        };
+        let modified = sent["client_modified"].as_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(modified).is_ok());
+        assert!(!modified.contains('.'), "fractional seconds in {}", modified);
+        let mut expected = commit("/Backup/a.txt");
+        expected["client_modified"] = json!(modified);
+        assert_eq!(sent, &expected);
+    }
+
+    #[tokio::test]
+    async fn update_mode_names_the_current_revision() {
+        let dir = TempDir::new();
+        let config = config_in(
+            &dir,
+            &[
+                ("UPLOAD_MODE", "update"),
+                ("AUTORENAME", "true"),
+                ("SET_CLIENT_MODIFIED", "false"),
+            ],
+        )
+        .unwrap();
+        let client =
+            MockClient { rev: Some("015f".to_string()), ..MockClient::default() };
+        upload(&client, &config, "a.txt", 10).await;
+
+        let mut expected = commit("/Backup/a.txt");
+        expected["autorename"] = json!(true);
+        expected["mode"] = json!({ ".tag": "update", "update": "015f" });
+        assert_eq!(
+            client.calls(),
+            [
+                Call::GetMetadata("/Backup/a.txt".to_string()),
+                Call::Upload { commit: expected, size: 10 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn large_file_goes_through_a_session() {
+        let dir = TempDir::new();
+        let config = chunked(&dir);
+        let client = MockClient::default();
+        upload(&client, &config, "big.txt", 10 * MIB).await;
+
+        assert_eq!(
+            client.calls(),
+            [
+                Call::Start,
+                append("new", 0, 4 * MIB),
+                append("new", 4 * MIB, 4 * MIB),
+                append("new", 8 * MIB, 2 * MIB),
+                Call::Finish {
+                    session_id: "new".to_string(),
+                    offset: 10 * MIB as u64,
+                    commit: commit("/Backup/big.txt"),
+                },
+            ]
+        );
+        assert_eq!(fs::read_dir(&config.upload_session_dir).unwrap().count(), 0);
+    }
+
+    /// Saves a session 4 MiB into sending bytes with `content_hash` for
+    /// `big.txt`, whose own content hash is `local_hash`, and returns its path.
+    fn saved_session(config: &Config, local_hash: &str, content_hash: &str) -> PathBuf {
+        let name = upload_state_name(local_hash, "/Backup/big.txt");
+        let state_path = config.upload_session_dir.join(format!("{}.json", name));
+        let state = UploadSessionState {
+            session_id: "saved".to_string(),
+            offset: 4 * MIB as u64,
+            content_hash: content_hash.to_string(),
+        };
+        save_session_state(&state_path, &state).unwrap();
+        state_path
+    }
+
+    #[tokio::test]
+    async fn saved_session_is_resumed_at_its_offset() {
+        let dir = TempDir::new();
+        let config = chunked(&dir);
+        let (_, local_hash) = source_file(&config, "big.txt", 10 * MIB);
+        let state_path = saved_session(&config, &local_hash, &local_hash);
+
+        let client = MockClient::default();
+        upload(&client, &config, "big.txt", 10 * MIB).await;
+
+        assert_eq!(
+            client.calls(),
+            [
+                append("saved", 4 * MIB, 4 * MIB),
+                append("saved", 8 * MIB, 2 * MIB),
+                Call::Finish {
+                    session_id: "saved".to_string(),
+                    offset: 10 * MIB as u64,
+                    commit: commit("/Backup/big.txt"),
+                },
+            ]
+        );
+        assert!(!state_path.exists());
+    }
+
+    #[tokio::test]
+    async fn session_for_other_bytes_is_started_over() {
+        let dir = TempDir::new();
+        let config = chunked(&dir);
+        let (_, local_hash) = source_file(&config, "big.txt", 10 * MIB);
+        let state_path = saved_session(&config, &local_hash, "of an earlier encryption");
+
+        let client = MockClient::default();
+        upload(&client, &config, "big.txt", 10 * MIB).await;
+
+        let calls = client.calls();
+        assert_eq!(calls[..2], [Call::Start, append("new", 0, 4 * MIB)]);
+        assert!(!state_path.exists());
+    }
+
+    #[tokio::test]
+    async fn session_dropbox_no_longer_has_is_started_over() {
+        let stale = [
+            json!({ ".tag": "incorrect_offset", "correct_offset": 0 }),
+            json!({ ".tag": "lookup_failed", "lookup_failed": { ".tag": "not_found" } }),
+            json!({ ".tag": "lookup_failed", "lookup_failed": { ".tag": "closed" } }),
+        ];
+        for error in stale {
+            let dir = TempDir::new();
+            let config = chunked(&dir);
+            let (_, local_hash) = source_file(&config, "big.txt", 10 * MIB);
+            let state_path = saved_session(&config, &local_hash, &local_hash);
+
+            let client = MockClient {
+                saved_rejected: Some((StatusCode::CONFLICT, error.clone())),
+                ..MockClient::default()
+            };
+            upload(&client, &config, "big.txt", 10 * MIB).await;
+
+            let calls = client.calls();
+            assert_eq!(
+                calls[..3],
+                [
+                    append("saved", 4 * MIB, 4 * MIB),
+                    Call::Start,
+                    append("new", 0, 4 * MIB)
+                ],
+                "after {}",
+                error
+            );
+            assert!(!state_path.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn other_session_failures_keep_the_saved_session() {
+        let failures = [
+            (StatusCode::UNAUTHORIZED, json!({ ".tag": "expired_access_token" })),
+            (StatusCode::CONFLICT, json!({ ".tag": "too_large" })),
+            (StatusCode::BAD_REQUEST, json!({ ".tag": "not_found" })),
+        ];
+        for (status, error) in failures {
+            let dir = TempDir::new();
+            let config = chunked(&dir);
+            let (_, local_hash) = source_file(&config, "big.txt", 10 * MIB);
+            let state_path = saved_session(&config, &local_hash, &local_hash);
+
+            let client = MockClient {
+                saved_rejected: Some((status, error.clone())),
+                ..MockClient::default()
+            };
+            let result = try_upload(&client, &config, "big.txt", 10 * MIB).await;
+
+            assert_eq!(
+                status == StatusCode::UNAUTHORIZED,
+                matches!(result, Err(UploadError::Unauthorized(_))),
+                "after HTTP {} {}",
+                status,
+                error
+            );
+            assert!(result.is_err(), "after HTTP {} {}", status, error);
+            assert_eq!(client.calls(), [append("saved", 4 * MIB, 4 * MIB)]);
+            assert!(state_path.exists());
+        }
+    }
+
+    /// `local_file` as uploaded to `/a.txt` with content hash `content_hash`,
+    /// and quick hash `quick_hash` if set.
+    fn logged(
+        log: &UploadLog,
+        local_file: &Path,
+        content_hash: &str,
+        quick_hash: Option<&str>,
+    ) {
+        log.record(&LogEntry {
+            path: local_file,
+            content_hash,
+            size: 0,
+            rev: None,
+            remote_path: "/a.txt",
+            quick_hash,
+        })
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn quick_hash_miss_is_not_hashed_in_full() {
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[]).unwrap();
+        let log = UploadLog::File(FileLog::open(&config.uploaded_files_log).unwrap());
+        let (local_file, local_hash) = source_file(&config, "a.txt", 3 * MIB);
+        // Logged by its content before quick hashes were.
+        logged(&log, &local_file, &local_hash, None);
+        let quick = quick_hash(&local_file).unwrap();
+
+        let lookup = look_up(&log, &local_file, Some(&quick)).await.unwrap();
+
+        assert_eq!(lookup, Lookup::New(None));
+        assert_eq!(look_up(&log, &local_file, None).await.unwrap(), Lookup::Logged);
+    }
+
+    #[tokio::test]
+    async fn quick_hash_match_is_confirmed_in_full() {
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[]).unwrap();
+        let log = UploadLog::File(FileLog::open(&config.uploaded_files_log).unwrap());
+        let (local_file, local_hash) = source_file(&config, "a.txt", 3 * MIB);
+        let quick = quick_hash(&local_file).unwrap();
+
+        logged(&log, &local_file, &local_hash, Some(&quick));
+        let lookup = look_up(&log, &local_file, Some(&quick)).await.unwrap();
+        assert_eq!(lookup, Lookup::Logged);
+
+        // Rewritten in the middle since, keeping its size, modified time and ends.
+        logged(&log, &local_file, "of other bytes", Some(&quick));
+        let lookup = look_up(&log, &local_file, Some(&quick)).await.unwrap();
+        assert_eq!(lookup, Lookup::New(Some(local_hash)));
+    }
+
+    #[test]
+    fn extension_routes_pick_the_subfolder() {
+        let dir = TempDir::new();
+        let config =
+            config_in(&dir, &[("EXT_ROUTES", "jpg:Photos"), ("PRESERVE_TREE", "true")])
+                .unwrap();
+        let path =
+            |name: &str| dropbox_path(&config, &config.current_directory.join(name));
+
+        assert_eq!(path("trip/IMG_1.JPG").unwrap(), "/Backup/Photos/trip/IMG_1.JPG");
+        assert_eq!(path("trip/notes.txt").unwrap(), "/Backup/trip/notes.txt");
+    }
+
+    #[test]
+    fn upload_state_depends_on_the_destination() {
+        let hash = "ab".repeat(32);
+        let name = |remote_path| upload_state_name(&hash, remote_path);
+        assert_ne!(name("/Backup/x/a.txt"), name("/Backup/y/a.txt"));
+        assert_eq!(name("/Backup/x/a.txt"), name("/Backup/x/a.txt"));
+        assert!(name("/Backup/x/a.txt").starts_with(&hash));
+    }
+}
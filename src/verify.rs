@@ -0,0 +1,165 @@
+//! Checking the upload log against what is actually stored remotely.
+
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use walkdir::WalkDir;
+
+use crate::backend::{AnyBackend, Backend};
+use crate::config::Config;
+use crate::files::{content_hash, content_hash_async};
+use crate::ledger::{LoggedUpload, UploadLog};
+use crate::upload::{dropbox_path, transfer};
+
+/// Results of a [`verify`] pass, by remote path.
+#[derive(Debug, Default)]
+pub struct VerifySummary {
+    pub present: usize,
+    /// Missing remotely and uploaded again from `uploaded_directory`.
+    pub reuploaded: Vec<String>,
+    /// Missing remotely, with no local copy left in `uploaded_directory`.
+    pub lost: Vec<String>,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+impl VerifySummary {
+    fn log(&self) {
+        info!(
+            "Verify: {} present, {} re-uploaded, {} lost, {} failed",
+            self.present,
+            self.reuploaded.len(),
+            self.lost.len(),
+            self.failed.len()
+        );
+        for path in &self.lost {
+            error!("  {}: missing remotely and no local copy left", path);
+        }
+        for (path, e) in &self.failed {
+            error!("  {}: {}", path, e);
+        }
+    }
+}
+
+/// Checks every entry of the upload log against the backend and re-uploads the
+/// ones that are missing there, e.g. because a crash separated logging from the
+/// upload actually finishing.
+///
+/// Uploaded files have been moved to `uploaded_directory`, so that is where
+/// the copies to re-upload come from, found by content hash. In a dry run
+/// missing files are only reported.
+pub async fn verify(config: &Config) -> Result<VerifySummary> {
+    let backend = AnyBackend::connect(config).await?;
+    let upload_log = UploadLog::open(config)?;
+    verify_with(&backend, config, &upload_log).await
+}
+
+pub(crate) async fn verify_with(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+) -> Result<VerifySummary> {
+    let entries = upload_log.entries()?;
+    info!("Verifying {} logged upload(s)", entries.len());
+
+    let mut summary = VerifySummary::default();
+    let mut missing = Vec::new();
+    let mut checks = stream::iter(entries)
+        .map(|entry| async move {
+            let remote_path = match &entry.remote_path {
+                Some(remote_path) => Ok(remote_path.clone()),
+                None => dropbox_path(config, &entry.path),
+            };
+            let exists = match &remote_path {
+                Ok(remote_path) => backend.exists(config, remote_path).await,
+                Err(_) => Ok(false),
+            };
+            (entry, remote_path, exists)
+        })
+        .buffer_unordered(config.max_concurrent_uploads);
+    while let Some((entry, remote_path, exists)) = checks.next().await {
+        let remote_path = match remote_path {
+            Ok(remote_path) => remote_path,
+            Err(e) => {
+                summary.failed.push((entry.path.display().to_string(), e));
+                continue;
+            }
+        };
+        match exists {
+            Ok(true) => summary.present += 1,
+            Ok(false) => {
+                warn!("Logged as uploaded but missing remotely: {}", remote_path);
+                missing.push((entry, remote_path));
+            }
+            Err(e) => summary.failed.push((remote_path, e)),
+        }
+    }
+
+    if !missing.is_empty() {
+        let copies = local_copies(config).await?;
+        for (entry, remote_path) in missing {
+            match reupload(backend, config, &copies, &entry, &remote_path).await {
+                Ok(true) => summary.reuploaded.push(remote_path),
+                Ok(false) => summary.lost.push(remote_path),
+                Err(e) => summary.failed.push((remote_path, e)),
+            }
+        }
+    }
+    summary.log();
+    Ok(summary)
+}
+
+/// Content hashes of the files in `uploaded_directory`, which may have been
+/// renamed on the way there.
+async fn local_copies(config: &Config) -> Result<HashMap<String, PathBuf>> {
+    let dir = config.uploaded_directory.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut copies = HashMap::new();
+        for entry in WalkDir::new(&dir).into_iter().filter_map(|e| e.ok()) {
+            if entry.file_type().is_file() {
+                copies.insert(content_hash(entry.path())?, entry.into_path());
+            }
+        }
+        Ok(copies)
+    })
+    .await?
+}
+
+/// Uploads the local copy of `entry` to `remote_path`, returning whether there
+/// was one. Entries without a content hash fall back to a file of the same
+/// name.
+async fn reupload(
+    backend: &impl Backend,
+    config: &Config,
+    copies: &HashMap<String, PathBuf>,
+    entry: &LoggedUpload,
+    remote_path: &str,
+) -> Result<bool> {
+    let local = match &entry.content_hash {
+        Some(hash) => copies.get(hash).cloned(),
+        None => entry
+            .path
+            .file_name()
+            .map(|name| config.uploaded_directory.join(name))
+            .filter(|path| path.is_file()),
+    };
+    let Some(local) = local else {
+        return Ok(false);
+    };
+    if config.dry_run {
+        info!("[dry-run] Would re-upload {:?} -> {}", local, remote_path);
+        return Ok(true);
+    }
+    let hash = match &entry.content_hash {
+        Some(hash) => hash.clone(),
+        None => content_hash_async(&local).await?,
+    };
+    transfer(backend, config, &local, &hash, remote_path, None).await?;
+    info!(
+        path:% = local.display(),
+        remote_path:% = remote_path;
+        "Re-uploaded {:?} -> {}", local, remote_path
+    );
+    Ok(true)
+}
@@ -0,0 +1,240 @@
+//! Continuous backup: uploading matching files as they appear or change, or
+//! re-running the backup pass on a fixed interval.
+
+use anyhow::{Context, Result, anyhow};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::backend::{AnyBackend, Backend};
+use crate::config::Config;
+use crate::ledger::UploadLog;
+use crate::scan::{matches_filters, matches_metadata_filters};
+use crate::upload::upload_file;
+use crate::{RunSummary, metrics};
+
+/// Uploads what is already in `current_directory`, then watches it (recursively
+/// if `recurse` is set) and uploads each new or modified matching file once it
+/// has gone `watch_debounce` without further events. Runs until the watcher
+/// fails.
+///
+/// Paths are only looked at once they are due, so the temporary file of an
+/// editor that writes elsewhere and renames into place is gone by then and only
+/// the final name is uploaded.
+///
+/// Due files go through a queue of `upload_queue_size` to the uploads, so a
+/// burst of files does not hold up watching; once the queue is full, events
+/// wait until uploads catch up.
+pub async fn watch(config: &Config) -> Result<()> {
+    let backend = AnyBackend::connect(config).await?;
+    let upload_log = UploadLog::open(config)?;
+
+    let (tx, events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // The receiver only goes away when `watch` returns.
+        let _ = tx.send(event);
+    })
+    .context("Creating file watcher")?;
+    let mode = if config.recurse {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher
+        .watch(&config.current_directory, mode)
+        .with_context(|| format!("Watching {:?}", config.current_directory))?;
+
+    crate::run_with(&backend, config, &upload_log).await?;
+    info!("Watching {:?} for new files", config.current_directory);
+
+    let (queue, queued) = mpsc::channel(config.upload_queue_size);
+    tokio::try_join!(
+        queue_due_files(config, events, queue),
+        upload_queued(&backend, config, &upload_log, queued)
+    )?;
+    Ok(())
+}
+
+/// Sends each path from `events` to `queue` once it has had no events for
+/// `watch_debounce` (or `min_file_age`, if longer) and still matches the
+/// filters. Only returns when the watcher stops.
+async fn queue_due_files(
+    config: &Config,
+    mut events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+    queue: mpsc::Sender<PathBuf>,
+) -> Result<()> {
+    // Last time each path saw an event.
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    // A file is last modified no later than its last event, so once it has been
+    // quiet this long it is also old enough for `min_file_age`.
+    let quiet_for = config.watch_debounce.max(config.min_file_age);
+    loop {
+        let next_due = pending.values().min().map(|last| *last + quiet_for);
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Ok(event)) => {
+                    // Not plain accesses: hashing a file for upload opens it too.
+                    if matches!(
+                        event.kind,
+                        EventKind::Create(_)
+                            | EventKind::Modify(_)
+                            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+                    ) {
+                        let now = Instant::now();
+                        for path in event.paths {
+                            pending.insert(path, now);
+                        }
+                    }
+                }
+                Some(Err(e)) => warn!("File watcher error: {}", e),
+                None => return Err(anyhow!("File watcher stopped")),
+            },
+            _ = time::sleep_until(next_due.unwrap_or_else(Instant::now)),
+                if next_due.is_some() =>
+            {
+                let now = Instant::now();
+                let mut due = Vec::new();
+                pending.retain(|path, last| {
+                    let quiet = now >= *last + quiet_for;
+                    if quiet {
+                        due.push(path.clone());
+                    }
+                    !quiet
+                });
+                due.retain(|path| {
+                    matches_filters(config, path)
+                        && fs::metadata(path).is_ok_and(|metadata| {
+                            metadata.is_file()
+                                && matches_metadata_filters(config, path, &metadata)
+                        })
+                });
+                for path in due {
+                    metrics::queued();
+                    // The uploads only stop once this sender is gone.
+                    let _ = queue.send(path).await;
+                }
+            }
+        }
+    }
+}
+
+/// Uploads the files sent to `queued`, `max_concurrent_uploads` at a time, until
+/// its sender is dropped. A summary is logged every time the queue runs dry.
+///
+/// Like [`crate::process_files`], uploads run on this task rather than being
+/// spawned. `fail_fast` has no pass to end here, so later files are still
+/// tried.
+async fn upload_queued(
+    backend: &impl Backend,
+    config: &Config,
+    upload_log: &UploadLog,
+    mut queued: mpsc::Receiver<PathBuf>,
+) -> Result<()> {
+    let mut in_flight = FuturesUnordered::new();
+    let mut summary = RunSummary::default();
+    let mut started = None;
+    let mut open = true;
+    loop {
+        tokio::select! {
+            file = queued.recv(),
+                if open && in_flight.len() < config.max_concurrent_uploads =>
+            {
+                match file {
+                    Some(file) => {
+                        started.get_or_insert_with(Instant::now);
+                        in_flight.push(async move {
+                            let result = upload_file(backend, config, upload_log, &file).await;
+                            (file, result)
+                        });
+                    }
+                    None => open = false,
+                }
+            }
+            Some((file, result)) = in_flight.next(), if !in_flight.is_empty() => {
+                summary.record(config, file, result);
+                metrics::dequeued();
+                if in_flight.is_empty()
+                    && queued.is_empty()
+                    && let Some(started) = started.take()
+                {
+                    summary.duration = started.elapsed();
+                    mem::take(&mut summary).log();
+                }
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+/// Runs a backup pass every `interval`, forever. Files already in the upload log
+/// are skipped as usual, and a pass that fails outright is logged and retried on
+/// the next tick. A pass that overruns `interval` delays the next one rather than
+/// starting it immediately.
+pub async fn poll(config: &Config, interval: Duration) -> Result<()> {
+    let backend = AnyBackend::connect(config).await?;
+    let upload_log = UploadLog::open(config)?;
+
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = crate::run_with(&backend, config, &upload_log).await {
+            error!("Backup pass failed: {}", e);
+        }
+        info!("Next pass in {:?}", interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, config_in};
+    use notify::event::{CreateKind, ModifyKind};
+    use std::path::Path;
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    fn event(kind: EventKind, path: &Path) -> notify::Result<notify::Event> {
+        Ok(notify::Event::new(kind).add_path(path.to_path_buf()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn files_are_queued_once_their_events_stop() {
+        let dir = TempDir::new();
+        let config = config_in(&dir, &[("WATCH_DEBOUNCE_MS", "2000")]).unwrap();
+        let (wanted, other) = (
+            config.current_directory.join("a.txt"),
+            config.current_directory.join("b.log"),
+        );
+        fs::write(&wanted, "x").unwrap();
+        fs::write(&other, "x").unwrap();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let (queue, mut queued) = mpsc::channel(8);
+        let task =
+            tokio::spawn(async move { queue_due_files(&config, events, queue).await });
+
+        events_tx.send(event(EventKind::Create(CreateKind::File), &wanted)).unwrap();
+        events_tx.send(event(EventKind::Create(CreateKind::File), &other)).unwrap();
+        time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(queued.try_recv(), Err(TryRecvError::Empty));
+
+        // Another write starts the wait over.
+        events_tx.send(event(EventKind::Modify(ModifyKind::Any), &wanted)).unwrap();
+        time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(queued.try_recv(), Err(TryRecvError::Empty));
+
+        time::sleep(Duration::from_millis(1000)).await;
+        assert_eq!(queued.try_recv(), Ok(wanted));
+        assert_eq!(queued.try_recv(), Err(TryRecvError::Empty));
+
+        drop(events_tx);
+        assert!(task.await.unwrap().is_err());
+    }
+}